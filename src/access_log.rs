@@ -0,0 +1,117 @@
+use std::{
+    io::Write,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+use serde_json::json;
+use tide::{Middleware, Next, Request};
+
+use crate::{client, config};
+
+/// Time spent in each stage of an image request. Stages that did not run,
+/// e.g. because the image came from the cache, are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    /// Parsing the request and finding the source image and its policy.
+    pub resolve: Option<Duration>,
+    /// Reading the requested region from the source image.
+    pub decode: Option<Duration>,
+    /// Scaling and rotating.
+    pub transform: Option<Duration>,
+    /// Converting to the requested quality and writing the output format.
+    pub encode: Option<Duration>
+}
+
+/// What a handler tells the access log about a request, attached to its response.
+#[derive(Debug, Clone, Default)]
+pub struct RequestLog {
+    pub canonical: Option<String>,
+    pub timings: Timings
+}
+
+/// Writes a JSON line for every request to stdout, apart from the other logs.
+pub struct AccessLog;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for AccessLog {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        // Without the query, it may hold API keys and signatures.
+        let path = req.url().path().to_owned();
+        let client = client::client_ip(&req).map(|ip| ip.to_string());
+
+        let res = next.run(req).await;
+        if !config::log_access() {
+            return Ok(res)
+        }
+
+        let details = res.ext::<RequestLog>().cloned().unwrap_or_default();
+        let line = json!({
+            "time": rfc3339(SystemTime::now()),
+            "method": method,
+            "path": path,
+            "canonical": details.canonical,
+            "status": u16::from(res.status()),
+            "bytes": res.len(),
+            "client": client,
+            "duration_ms": millis(start.elapsed()),
+            "timings_ms": {
+                "resolve": details.timings.resolve.map(millis),
+                "decode": details.timings.decode.map(millis),
+                "transform": details.timings.transform.map(millis),
+                "encode": details.timings.encode.map(millis)
+            }
+        });
+
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        if let Err(e) = writeln!(out, "{}", line) {
+            log::warn!("Cannot write access log --- {:?}", e);
+        }
+        Ok(res)
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// A UTC timestamp such as `2024-05-01T12:30:00.250Z`.
+fn rfc3339(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, since_epoch.subsec_millis()
+    )
+}
+
+/// Year, month and day of a number of days since 1970-01-01, after Howard Hinnant.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_827_696_250)), "2000-02-29T12:34:56.250Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1_735_689_599)), "2024-12-31T23:59:59.000Z");
+        assert_eq!(millis(Duration::from_micros(1234)), 1.234);
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+use async_std::task;
+use lazy_static::lazy_static;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, TransactionBehavior, params};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, wif_error::WifError};
+
+const SECS_PER_DAY: u64 = 86400;
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a charge waits for another process writing to the usage database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    pub static ref API_KEYS: ApiKeys = ApiKeys::open(config::api_keys(), config::api_keys_database(), config::api_keys_usage_path());
+}
+
+/// A partner's key with its daily quotas. Quotas that are not set are unlimited.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub key: String,
+    /// Usage is counted by name, so a key can be replaced without resetting it.
+    pub name: String,
+    #[serde(default)]
+    pub daily_pixels: Option<u64>,
+    #[serde(default)]
+    pub daily_requests: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    /// Days since the epoch, in UTC.
+    day: u64,
+    requests: u64,
+    pixels: u64
+}
+
+/// API keys from the configuration file and an optional SQLite database, and
+/// what each of them used today.
+///
+/// Keys from the database are read at startup and then once a minute, so rows
+/// can be added while the server is running. The key database is only ever
+/// opened read-only. Usage is recorded in a SQLite database of its own, one
+/// transaction per charge, so no count is lost when the process dies. It is
+/// only opened, and created, once a key is first used, so deployments without
+/// keys never write it.
+pub struct ApiKeys {
+    configured: RwLock<Vec<ApiKey>>,
+    database: Option<String>,
+    keys: RwLock<HashMap<String, ApiKey>>,
+    usage_path: String,
+    usage: Mutex<Option<Connection>>
+}
+
+impl ApiKeys {
+    pub fn open(configured: Vec<ApiKey>, database: Option<String>, usage_path: String) -> Self {
+        let keys = ApiKeys {
+            configured: RwLock::new(configured),
+            database,
+            keys: RwLock::new(HashMap::new()),
+            usage_path,
+            usage: Mutex::new(None)
+        };
+        keys.reload();
+        keys
+    }
+
+    /// Replaces the keys from the configuration file, e.g. after it was reloaded.
+    pub fn set_configured(&self, configured: Vec<ApiKey>) {
+        if let Ok(mut c) = self.configured.write() {
+            *c = configured;
+        }
+        self.reload();
+    }
+
+    /// Reads the keys again. Keys from the configuration file win over equal
+    /// keys from the database.
+    pub fn reload(&self) {
+        let mut keys = HashMap::new();
+        if let Some(path) = &self.database {
+            match read_database(path) {
+                Ok(k) => keys.extend(k.into_iter().map(|k| (k.key.clone(), k))),
+                Err(e) => log::error!("Cannot read API keys from {} --- {}", path, e)
+            }
+        }
+        if let Ok(configured) = self.configured.read() {
+            keys.extend(configured.iter().map(|k| (k.key.clone(), k.clone())));
+        }
+
+        if let Ok(mut current) = self.keys.write() {
+            *current = keys;
+        }
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<ApiKey> {
+        self.keys.read().ok()?.get(key).cloned()
+    }
+
+    /// Rejects a request producing `pixels` pixels with 429 until midnight UTC
+    /// if it would exceed the key's daily quotas, without counting it. Lets
+    /// requests be turned away before they are rendered.
+    ///
+    /// Reads the usage database, so it blocks.
+    pub fn check(&self, key: &ApiKey, pixels: u64) -> Result<(), WifError> {
+        self.with_usage(key, |conn, today| {
+            let usage = usage_on(conn, &key.name, today)?;
+            Ok(within_quotas(key, &usage, pixels))
+        })
+    }
+
+    /// Counts a request that produced `pixels` pixels against the key's daily
+    /// quotas, or rejects it like `check` if that would exceed them. Only
+    /// requests that were answered successfully are counted.
+    ///
+    /// Writes to the usage database, so it blocks.
+    pub fn charge(&self, key: &ApiKey, pixels: u64) -> Result<(), WifError> {
+        self.with_usage(key, |conn, today| record(conn, key, pixels, today))
+    }
+
+    fn with_usage(&self, key: &ApiKey, f: impl FnOnce(&mut Connection, u64) -> Result<bool, rusqlite::Error>) -> Result<(), WifError> {
+        let now = now();
+        let mut usage = match self.usage.lock() {
+            Ok(v) => v,
+            Err(_) => return Err(WifError::internal_error("API key usage is poisoned".to_owned()))
+        };
+        let conn = usage.get_or_insert_with(|| open_usage(&self.usage_path).unwrap_or_else(|e| {
+            log::error!("Cannot open API key usage database {} --- {}, usage is only counted until the next restart", self.usage_path, e);
+            open_usage(":memory:").expect("SQLite can always open an in-memory database")
+        }));
+
+        match f(conn, now / SECS_PER_DAY) {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                let retry_after = SECS_PER_DAY - now % SECS_PER_DAY;
+                Err(WifError::too_many_requests(format!("Daily quota of API key {} is used up", key.name), retry_after))
+            },
+            Err(e) => {
+                log::error!("Cannot record usage of API key {} --- {}", key.name, e);
+                Err(WifError::internal_error("Internal Server Error".to_owned()))
+            }
+        }
+    }
+}
+
+fn open_usage(path: &str) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // With a write-ahead log, a commit does not wait for the disk.
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_key_usage (
+            name TEXT PRIMARY KEY,
+            day INTEGER NOT NULL,
+            requests INTEGER NOT NULL,
+            pixels INTEGER NOT NULL
+        )",
+        []
+    )?;
+    Ok(conn)
+}
+
+/// Adds a request to today's usage of the key, unless that would exceed one
+/// of its quotas. Returns whether it was added.
+fn record(conn: &mut Connection, key: &ApiKey, pixels: u64, today: u64) -> Result<bool, rusqlite::Error> {
+    // Taking the write lock up front keeps other processes from charging in between.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let usage = usage_on(&tx, &key.name, today)?;
+    if !within_quotas(key, &usage, pixels) {
+        return Ok(false)
+    }
+
+    tx.execute(
+        "INSERT INTO api_key_usage (name, day, requests, pixels) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (name) DO UPDATE SET day = excluded.day, requests = excluded.requests, pixels = excluded.pixels",
+        params![key.name, today as i64, (usage.requests + 1) as i64, usage.pixels.saturating_add(pixels) as i64]
+    )?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// What the key named `name` used on `today`.
+fn usage_on(conn: &Connection, name: &str, today: u64) -> Result<Usage, rusqlite::Error> {
+    let stored = conn.query_row(
+        "SELECT day, requests, pixels FROM api_key_usage WHERE name = ?1",
+        [name],
+        |row| Ok(Usage {
+            day: row.get::<_, i64>(0)? as u64,
+            requests: row.get::<_, i64>(1)? as u64,
+            pixels: row.get::<_, i64>(2)? as u64
+        })
+    ).optional()?;
+    Ok(match stored {
+        Some(u) if u.day == today => u,
+        _ => Usage { day: today, requests: 0, pixels: 0 }
+    })
+}
+
+fn within_quotas(key: &ApiKey, usage: &Usage, pixels: u64) -> bool {
+    let over_requests = key.daily_requests.map(|q| usage.requests + 1 > q).unwrap_or(false);
+    let over_pixels = key.daily_pixels.map(|q| usage.pixels.saturating_add(pixels) > q).unwrap_or(false);
+    !(over_requests || over_pixels)
+}
+
+/// Reads the `api_keys` table. The database belongs to whoever manages the
+/// keys, so it is opened read-only and never created or changed.
+fn read_database(path: &str) -> Result<Vec<ApiKey>, rusqlite::Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT key, name, daily_pixels, daily_requests FROM api_keys")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ApiKey {
+            key: row.get(0)?,
+            name: row.get(1)?,
+            daily_pixels: row.get::<_, Option<i64>>(2)?.map(|n| n.max(0) as u64),
+            daily_requests: row.get::<_, Option<i64>>(3)?.map(|n| n.max(0) as u64)
+        })
+    })?;
+    rows.collect()
+}
+
+/// Periodically reads the keys from the database again, if there is one.
+pub fn spawn_maintenance() {
+    // Checked on the configuration, so that API_KEYS is not set up for nothing.
+    if config::api_keys_database().is_some() {
+        task::spawn(async {
+            loop {
+                task::sleep(RELOAD_INTERVAL).await;
+                task::spawn_blocking(|| API_KEYS.reload()).await;
+            }
+        });
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("wif-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn enforces_daily_quotas() {
+        let keys = ApiKeys::open(vec![], None, ":memory:".to_owned());
+        let key = ApiKey { key: "k".to_owned(), name: "partner".to_owned(), daily_pixels: Some(1000), daily_requests: Some(3) };
+
+        assert!(keys.charge(&key, 600).is_ok());
+        assert!(keys.charge(&key, 600).is_err(), "pixel quota");
+        assert!(keys.charge(&key, 400).is_ok());
+        assert!(keys.charge(&key, 0).is_ok());
+        let err = keys.charge(&key, 0).unwrap_err();
+        assert_eq!(err.status, tide::StatusCode::TooManyRequests);
+        assert!(err.retry_after.map(|s| s > 0 && s <= SECS_PER_DAY).unwrap_or(false));
+    }
+
+    #[test]
+    fn checks_quotas_without_counting() {
+        let keys = ApiKeys::open(vec![], None, ":memory:".to_owned());
+        let key = ApiKey { key: "k".to_owned(), name: "partner".to_owned(), daily_pixels: Some(1000), daily_requests: Some(1) };
+
+        for _ in 0..3 {
+            assert!(keys.check(&key, 1000).is_ok());
+        }
+        assert!(keys.check(&key, 1001).is_err());
+        assert!(keys.charge(&key, 1000).is_ok());
+        assert_eq!(keys.check(&key, 0).unwrap_err().status, tide::StatusCode::TooManyRequests);
+    }
+
+    #[test]
+    fn keeps_usage_across_restarts() {
+        let path = temp_path("usage");
+        let key = ApiKey { key: "k".to_owned(), name: "partner".to_owned(), daily_pixels: None, daily_requests: Some(1) };
+
+        let keys = ApiKeys::open(vec![], None, path.clone());
+        // Nothing is written before a key is used.
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(keys.charge(&key, 0).is_ok());
+        // Nothing is flushed: the charge is on disk once it returns.
+        let reopened = ApiKeys::open(vec![], None, path.clone());
+        assert!(reopened.charge(&key, 0).is_err());
+        drop((keys, reopened));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn starts_a_new_day_from_zero() {
+        let mut conn = open_usage(":memory:").unwrap();
+        let key = ApiKey { key: "k".to_owned(), name: "partner".to_owned(), daily_pixels: None, daily_requests: Some(1) };
+
+        assert!(record(&mut conn, &key, 10, 100).unwrap());
+        assert!(!record(&mut conn, &key, 10, 100).unwrap());
+        assert!(record(&mut conn, &key, 10, 101).unwrap());
+    }
+
+    #[test]
+    fn reads_the_key_database_without_changing_it() {
+        let path = temp_path("keys");
+        assert!(read_database(&path).is_err());
+        assert!(!std::path::Path::new(&path).exists(), "a missing database is not created");
+
+        Connection::open(&path).unwrap().execute_batch(
+            "CREATE TABLE api_keys (key TEXT PRIMARY KEY, name TEXT NOT NULL UNIQUE, daily_pixels INTEGER, daily_requests INTEGER);
+             INSERT INTO api_keys VALUES ('secret', 'partner', 1000, NULL);"
+        ).unwrap();
+        let keys = read_database(&path).unwrap();
+        assert_eq!(keys, vec![ApiKey { key: "secret".to_owned(), name: "partner".to_owned(), daily_pixels: Some(1000), daily_requests: None }]);
+
+        let tables: i64 = Connection::open(&path).unwrap()
+            .query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 1);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant}
+};
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::SaltString
+};
+use lazy_static::lazy_static;
+
+use crate::config;
+
+/// How long the login command may take before it is killed and the login refused.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a running login command is checked on.
+const COMMAND_POLL: Duration = Duration::from_millis(20);
+
+lazy_static! {
+    /// Checked against for unknown users, so they take as long as known ones.
+    static ref DUMMY_HASH: String = hash_password("").unwrap_or_default();
+}
+
+/// Decides whether a user may see restricted images.
+pub trait LoginCheck: Send + Sync {
+    fn check(&self, username: &str, password: &str) -> bool;
+}
+
+/// Users from the configuration file, with an Argon2 hash of their password
+/// in the PHC string format, as written by `wif hash-password`.
+pub struct ConfiguredUsers {
+    users: HashMap<String, String>
+}
+
+impl LoginCheck for ConfiguredUsers {
+    fn check(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => verify_password(hash, password),
+            None => {
+                verify_password(&DUMMY_HASH, password);
+                false
+            }
+        }
+    }
+}
+
+/// An external program, e.g. a script asking an LDAP server. It gets the
+/// username and the password as two lines on stdin and grants access by
+/// exiting with status 0 within `timeout`.
+pub struct LoginCommand {
+    command: String,
+    timeout: Duration
+}
+
+impl LoginCheck for LoginCommand {
+    fn check(&self, username: &str, password: &str) -> bool {
+        let mut child = match Command::new(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Cannot run login command {} --- {:?}", self.command, e);
+                return false
+            }
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = writeln!(stdin, "{}\n{}", username, password) {
+                log::error!("Cannot pass credentials to login command {} --- {:?}", self.command, e);
+            }
+        }
+        let started = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return status.success(),
+                Ok(None) if started.elapsed() < self.timeout => thread::sleep(COMMAND_POLL),
+                Ok(None) => {
+                    log::error!("Login command {} took longer than {:?}, killing it", self.command, self.timeout);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return false
+                },
+                Err(e) => {
+                    log::error!("Login command {} failed --- {:?}", self.command, e);
+                    return false
+                }
+            }
+        }
+    }
+}
+
+/// The login command if one is configured, the configured users otherwise.
+/// Built for each login, so changes to the configuration apply right away.
+pub fn from_config() -> Box<dyn LoginCheck> {
+    match config::auth_login_command() {
+        Some(command) => Box::new(LoginCommand { command, timeout: COMMAND_TIMEOUT }),
+        None => Box::new(ConfiguredUsers { users: config::auth_users().into_iter().collect() })
+    }
+}
+
+/// A salted Argon2id hash of a password in the PHC string format.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|e| e.to_string())?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Checks a PHC string, so it can be rejected when the configuration is read.
+pub fn check_hash(hash: &str) -> Result<(), String> {
+    let parsed = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+    match parsed.algorithm.as_str() {
+        "argon2id" | "argon2i" | "argon2d" => Ok(()),
+        other => Err(format!("{} is not supported, only Argon2", other))
+    }
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(h) => Argon2::default().verify_password(password.as_bytes(), &h).is_ok(),
+        Err(e) => {
+            log::error!("Invalid password hash --- {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_configured_users() {
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("secret").unwrap(), "hashes must be salted");
+        let users = ConfiguredUsers {
+            users: vec![("reader".to_owned(), hash)].into_iter().collect()
+        };
+
+        assert!(users.check("reader", "secret"));
+        assert!(!users.check("reader", "Secret"));
+        assert!(!users.check("nobody", "secret"));
+        assert!(!users.check("reader", ""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_the_login_command_with_a_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wif-login-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            LoginCommand { command: path.to_string_lossy().into_owned(), timeout: Duration::from_millis(500) }
+        };
+
+        let check = script("check", "read user; read pass; [ \"$user\" = reader ] && [ \"$pass\" = secret ]");
+        assert!(check.check("reader", "secret"));
+        assert!(!check.check("reader", "wrong"));
+
+        let started = Instant::now();
+        assert!(!script("hang", "exec sleep 30").check("reader", "secret"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accepts_only_argon2_hashes() {
+        assert!(check_hash(&hash_password("secret").unwrap()).is_ok());
+        // The unsalted SHA-256 hex digests of earlier versions.
+        assert!(check_hash("2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b").is_err());
+        assert!(check_hash("$pbkdf2-sha256$i=1000$c2FsdA$aGFzaA").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use serde_json::{Value, json};
+
+use crate::{
+    config, glob,
+    iiif::{
+        img_info::{ImgView, Rect, decode_identifier},
+        version::ApiVersion
+    },
+    wif_error::WifError
+};
+
+pub mod login;
+pub mod session;
+
+pub const AUTH_CONTEXT: &str = "http://iiif.io/api/auth/2/context.json";
+pub const SESSION_COOKIE: &str = "wif_session";
+
+/// Whether an image is only shown in full to logged in users.
+pub fn is_restricted(img: &ImgView) -> bool {
+    let rel_path = match decode_identifier(&img.identifier) {
+        Ok(p) => p,
+        Err(_) => return false
+    };
+    config::auth_restricted().iter().any(|p| glob::matches(p, &rel_path))
+}
+
+/// Lets image requests for restricted images through if the session is logged in,
+/// or if the result is no more detailed than the degraded substitute.
+pub fn check_image(img: &ImgView, section: &Rect, target: &Rect, session_id: Option<&str>) -> Result<(), WifError> {
+    if !is_restricted(img)
+        || within_degraded(&img.dimensions, section, target, config::auth_degraded_size())
+        || session_id.and_then(session::user).is_some() {
+        return Ok(())
+    }
+    Err(WifError::unauthorized(format!("{} is restricted, please log in", img.identifier)))
+}
+
+/// Whether the whole image, at the scale `section` is rendered to `target`,
+/// fits the degraded size. Judging the output alone would let small tiles of
+/// full resolution through, which can be stitched back together.
+fn within_degraded(img: &Rect, section: &Rect, target: &Rect, degraded: u32) -> bool {
+    let scaled = |whole: u32, part: u32, out: u32| (whole as u64 * out as u64).div_ceil(part as u64);
+    scaled(img.width, section.width, target.width) <= degraded as u64
+        && scaled(img.height, section.height, target.height) <= degraded as u64
+}
+
+pub fn access_service_id() -> String {
+    format!("{}/auth/login", ApiVersion::V3.base_uri())
+}
+
+fn degraded_substitute(img: &ImgView) -> Value {
+    let size = config::auth_degraded_size();
+    json!({
+        "id": format!("{}/{}/full/!{},{}/0/default.jpg", ApiVersion::V3.base_uri(), img.identifier, size, size),
+        "type": "Image",
+        "format": "image/jpeg"
+    })
+}
+
+/// The probe service with its access, token and logout services, for the
+/// `service` list of a restricted image's info.json.
+pub fn probe_service(img: &ImgView) -> Value {
+    let base = ApiVersion::V3.base_uri();
+    json!({
+        "id": format!("{}/auth/probe/{}", base, img.identifier),
+        "type": "AuthProbeService2",
+        "service": [{
+            "id": access_service_id(),
+            "type": "AuthAccessService2",
+            "profile": "active",
+            "label": { "none": [config::auth_label()] },
+            "confirmLabel": { "none": ["Log in"] },
+            "service": [
+                {
+                    "id": format!("{}/auth/token", base),
+                    "type": "AuthAccessTokenService2"
+                },
+                {
+                    "id": format!("{}/auth/logout", base),
+                    "type": "AuthLogoutService2",
+                    "label": { "none": ["Log out"] }
+                }
+            ]
+        }]
+    })
+}
+
+/// The probe result for an image and the bearer token sent along, if any.
+pub fn probe(img: &ImgView, token: Option<&str>) -> Value {
+    if !is_restricted(img) || token.and_then(session::token_user).is_some() {
+        return json!({
+            "@context": AUTH_CONTEXT,
+            "type": "AuthProbeResult2",
+            "status": 200
+        })
+    }
+
+    json!({
+        "@context": AUTH_CONTEXT,
+        "type": "AuthProbeResult2",
+        "status": 401,
+        "substitute": [degraded_substitute(img)],
+        "heading": { "none": ["Restricted image"] },
+        "note": { "none": ["Only a preview is shown. Log in to see the full image."] }
+    })
+}
+
+/// The answer of the token service: an access token for a logged in session,
+/// an error otherwise.
+pub fn access_token(session_id: Option<&str>, message_id: Option<&str>) -> Value {
+    let mut message = match session_id.and_then(session::issue_token) {
+        Some((token, expires_in)) => json!({
+            "@context": AUTH_CONTEXT,
+            "type": "AuthAccessToken2",
+            "accessToken": token,
+            "expiresIn": expires_in
+        }),
+        None => json!({
+            "@context": AUTH_CONTEXT,
+            "type": "AuthAccessTokenError2",
+            "profile": "missingAspect",
+            "heading": { "none": ["Not logged in"] },
+            "note": { "none": ["Please log in to see restricted images."] }
+        })
+    };
+    if let Some(id) = message_id {
+        message["messageId"] = Value::String(id.to_owned());
+    }
+    message
+}
+
+
+// PAGES
+/// The login form of the access service.
+pub fn login_page(origin: &str, failed: bool) -> String {
+    let error = if failed { "<p><strong>Wrong username or password.</strong></p>" } else { "" };
+    page(&config::auth_label(), &format!(
+        "{}<form method=\"post\" action=\"{}\">\
+        <input type=\"hidden\" name=\"origin\" value=\"{}\">\
+        <p><label>Username <input name=\"username\" autocomplete=\"username\"></label></p>\
+        <p><label>Password <input name=\"password\" type=\"password\" autocomplete=\"current-password\"></label></p>\
+        <p><button type=\"submit\">Log in</button></p>\
+        </form>",
+        error, escape_html(&access_service_id()), escape_html(origin)))
+}
+
+/// Closes the window the client opened for the access or logout service.
+pub fn closing_page(message: &str) -> String {
+    page(message, "<script>window.close();</script>")
+}
+
+/// Hands a token service message to the client window that embedded us in an iframe.
+pub fn token_page(message: &Value, origin: &str) -> String {
+    page("", &format!(
+        "<script>window.parent.postMessage({}, {});</script>",
+        script_json(message), script_json(&Value::String(origin.to_owned()))))
+}
+
+fn page(title: &str, body: &str) -> String {
+    let title = escape_html(title);
+    format!("<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body><h1>{}</h1>{}</body></html>", title, title, body)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// JSON that cannot end the surrounding script element.
+fn script_json(v: &Value) -> String {
+    v.to_string().replace('<', "\\u003c")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMG: Rect = Rect { width: 4000, height: 3000 };
+
+    #[test]
+    fn allows_only_the_degraded_scale_of_the_whole_image() {
+        assert!(within_degraded(&IMG, &IMG, &Rect { width: 200, height: 150 }, 200));
+        assert!(!within_degraded(&IMG, &IMG, &Rect { width: 400, height: 300 }, 200));
+        // A small tile at full resolution would reveal the image piece by piece.
+        assert!(!within_degraded(&IMG, &Rect { width: 200, height: 200 }, &Rect { width: 200, height: 200 }, 200));
+        assert!(within_degraded(&IMG, &Rect { width: 2000, height: 1500 }, &Rect { width: 100, height: 75 }, 200));
+        assert!(!within_degraded(&IMG, &Rect { width: 2000, height: 1500 }, &Rect { width: 101, height: 75 }, 200));
+    }
+}
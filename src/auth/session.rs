@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH}
+};
+use lazy_static::lazy_static;
+use rand::RngCore;
+
+use crate::config;
+
+lazy_static! {
+    static ref SESSIONS: Mutex<Sessions> = Mutex::new(Sessions::default());
+}
+
+#[derive(Debug)]
+struct Session {
+    user: String,
+    expires: u64,
+    /// The access token issued for the session, handed out again on every
+    /// call of the token service, so polling it cannot pile up tokens.
+    token: Option<String>
+}
+
+/// Logged in users by session cookie, and the access tokens issued to them.
+/// Both are kept in memory only, so a restart logs everybody out.
+#[derive(Debug, Default)]
+struct Sessions {
+    sessions: HashMap<String, Session>,
+    /// Session id by access token.
+    tokens: HashMap<String, String>
+}
+
+impl Sessions {
+    fn prune(&mut self) {
+        let now = now();
+        self.sessions.retain(|_, s| s.expires > now);
+        let sessions = &self.sessions;
+        self.tokens.retain(|_, id| sessions.contains_key(id));
+    }
+
+    fn get(&self, id: &str) -> Option<&Session> {
+        self.sessions.get(id).filter(|s| s.expires > now())
+    }
+}
+
+/// Starts a session for the user and returns its id, to be sent as cookie.
+pub fn start(user: &str) -> String {
+    let id = random_id();
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.prune();
+        sessions.sessions.insert(id.clone(), Session {
+            user: user.to_owned(),
+            expires: now() + config::auth_session_ttl(),
+            token: None
+        });
+    }
+    id
+}
+
+pub fn end(id: &str) {
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.sessions.remove(id);
+        sessions.tokens.retain(|_, s| s != id);
+    }
+}
+
+/// The user of a session that has not expired yet.
+pub fn user(id: &str) -> Option<String> {
+    let sessions = SESSIONS.lock().ok()?;
+    sessions.get(id).map(|s| s.user.clone())
+}
+
+/// Issues the access token of a session, valid as long as the session.
+/// Returns the token and the seconds until it expires.
+pub fn issue_token(id: &str) -> Option<(String, u64)> {
+    let mut guard = SESSIONS.lock().ok()?;
+    let sessions = &mut *guard;
+    let now = now();
+    let session = sessions.sessions.get_mut(id).filter(|s| s.expires > now)?;
+
+    let token = session.token.get_or_insert_with(random_id).clone();
+    sessions.tokens.insert(token.clone(), id.to_owned());
+    Some((token, session.expires.saturating_sub(now)))
+}
+
+/// The user an access token was issued to, as long as their session lasts.
+pub fn token_user(token: &str) -> Option<String> {
+    let sessions = SESSIONS.lock().ok()?;
+    let id = sessions.tokens.get(token)?;
+    sessions.get(id).map(|s| s.user.clone())
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issues_one_token_per_session() {
+        let id = start("reader");
+        let (token, expires_in) = issue_token(&id).unwrap();
+        assert!(expires_in > 0);
+        for _ in 0..10 {
+            assert_eq!(issue_token(&id).unwrap().0, token);
+        }
+        assert_eq!(token_user(&token).as_deref(), Some("reader"));
+        assert_eq!(SESSIONS.lock().unwrap().tokens.values().filter(|s| **s == id).count(), 1);
+
+        end(&id);
+        assert_eq!(token_user(&token), None);
+        assert_eq!(issue_token(&id), None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+use serde_json::Value;
+
+use crate::{config::{self, Source}, listen::ListenAddr};
+
+pub const USAGE: &str = "Usage:
+    wif [serve] [options]
+    wif sign <identifier> [--request <region>/<size>/<rotation>/<quality>] [--expires-in <secs>] [--key <id>] [--v2] [--config <file>]
+    wif hash-password < <password file>
+
+Options:
+    --config <file>          Configuration file (.json, .toml or .yaml)
+    --ip <a.b.c.d>           Address to listen on
+    --port <port>            Port to listen on
+    --listen <address>       Address to listen on instead of --ip and --port, e.g.
+                             [::]:8000 or unix:/run/wif.sock. Can be given several times
+    --image-path <dir>       Folder the images are read from
+    --base-address <url>     Address the server is reached at from outside
+    --set <setting>=<value>  Any other setting, e.g. --set auth.degraded_size=150
+    --no-write-default       Do not write a default config.json if there is no configuration file
+    -h, --help               Show this message
+
+Settings are taken from the defaults, then the configuration file, then WIF_*
+environment variables, then options, each overriding the ones before. Nested
+settings are separated by __ in variable names, e.g. WIF_AUTH__DEGRADED_SIZE=150.
+WIF_CONFIG and WIF_NO_WRITE_DEFAULT stand in for the options of the same name.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    /// `wif sign`, with the arguments left for `signing::cli`.
+    Sign(Vec<String>),
+    HashPassword,
+    Help
+}
+
+/// Parses the arguments after the program name.
+pub fn parse(args: &[String]) -> Result<(Command, Source), String> {
+    if args.first().map(|a| a.as_str()) == Some("hash-password") {
+        return match args.get(1) {
+            None => Ok((Command::HashPassword, Source::default())),
+            Some(a) => Err(format!("Unknown option {}\n\n{}", a, USAGE))
+        }
+    }
+
+    let (sign, args) = match args.first().map(|a| a.as_str()) {
+        Some("sign") => (true, &args[1..]),
+        Some("serve") => (false, &args[1..]),
+        _ => (false, args)
+    };
+
+    let mut source = Source::default();
+    let mut rest = vec![];
+    let mut listen = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok((Command::Help, source)),
+            "--config" => source.path = Some(PathBuf::from(value(arg)?)),
+            "--no-write-default" => source.no_write_default = true,
+            "--ip" if !sign => {
+                let ip: std::net::Ipv4Addr = value(arg)?.parse().map_err(|_| "--ip needs an IPv4 address".to_owned())?;
+                source.overrides.push(("ip".to_owned(), serde_json::json!(ip.octets())));
+            },
+            "--port" if !sign => {
+                let port: u16 = value(arg)?.parse().map_err(|_| "--port needs a port number".to_owned())?;
+                source.overrides.push(("port".to_owned(), Value::from(port)));
+            },
+            "--listen" if !sign => {
+                let addr: ListenAddr = value(arg)?.parse()?;
+                listen.push(Value::String(addr.to_string()));
+            },
+            "--image-path" if !sign => source.overrides.push(("image_path".to_owned(), Value::String(value(arg)?))),
+            "--base-address" if !sign => source.overrides.push(("base_address".to_owned(), Value::String(value(arg)?))),
+            "--set" if !sign => {
+                let setting = value(arg)?;
+                let (path, v) = setting.split_once('=').ok_or("--set needs <setting>=<value>")?;
+                source.overrides.push((path.trim().to_owned(), config::json_or_string(v)));
+            },
+            _ if sign => rest.push(arg.clone()),
+            a => return Err(format!("Unknown option {}\n\n{}", a, USAGE))
+        }
+    }
+
+    if !listen.is_empty() {
+        source.overrides.push(("listen".to_owned(), Value::Array(listen)));
+    }
+
+    if sign {
+        Ok((Command::Sign(rest), source))
+    } else {
+        Ok((Command::Serve, source))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_owned()).collect()
+    }
+
+    #[test]
+    fn parses_serve_options() {
+        let (command, source) = parse(&args("serve --config /etc/wif.toml --port 8080 --image-path /srv --set auth.label=Hi --no-write-default")).unwrap();
+        assert_eq!(command, Command::Serve);
+        assert_eq!(source.path, Some(PathBuf::from("/etc/wif.toml")));
+        assert!(source.no_write_default);
+        assert_eq!(source.overrides, vec![
+            ("port".to_owned(), Value::from(8080)),
+            ("image_path".to_owned(), Value::from("/srv")),
+            ("auth.label".to_owned(), Value::from("Hi"))
+        ]);
+
+        let (_, source) = parse(&args("--listen [::]:8000 --listen unix:/run/wif.sock")).unwrap();
+        assert_eq!(source.overrides, vec![("listen".to_owned(), serde_json::json!(["[::]:8000", "unix:/run/wif.sock"]))]);
+        assert!(parse(&args("--listen localhost")).is_err());
+
+        assert_eq!(parse(&[]).unwrap().0, Command::Serve);
+        assert_eq!(parse(&args("--port 8080")).unwrap().0, Command::Serve);
+        assert!(parse(&args("serve --port http")).is_err());
+        assert!(parse(&args("serve --bogus")).is_err());
+    }
+
+    #[test]
+    fn leaves_sign_arguments_to_signing() {
+        let (command, source) = parse(&args("sign book/page1.png --config wif.yaml --expires-in 60")).unwrap();
+        assert_eq!(command, Command::Sign(args("book/page1.png --expires-in 60")));
+        assert_eq!(source.path, Some(PathBuf::from("wif.yaml")));
+    }
+
+    #[test]
+    fn parses_hash_password() {
+        assert_eq!(parse(&args("hash-password")).unwrap().0, Command::HashPassword);
+        assert!(parse(&args("hash-password secret")).is_err());
+    }
+}
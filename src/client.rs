@@ -0,0 +1,181 @@
+use std::{net::IpAddr, str::FromStr};
+use tide::Request;
+
+use crate::config;
+
+/// An address, or a network in CIDR notation such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None)
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("{} is not an IP address", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.trim().parse::<u8>() {
+                Ok(n) if n <= max => n,
+                _ => return Err(format!("{} has an invalid prefix length", s))
+            },
+            None => max
+        };
+        // A v4-mapped network such as `::ffff:10.0.0.0/104` is an IPv4 one.
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(IpRange { addr: IpAddr::V4(v4), prefix: prefix - 96 }),
+            _ => Ok(IpRange { addr, prefix })
+        }
+    }
+}
+
+impl IpRange {
+    /// Whether `ip` is in the range. IPv4 peers of dual-stack listeners arrive
+    /// as `::ffff:a.b.c.d` and match IPv4 ranges.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+/// The API key sent in the `X-API-Key` header or the `api_key` query parameter, if any.
+pub fn api_key(req: &Request<()>) -> Option<String> {
+    match req.header("X-API-Key") {
+        Some(h) => Some(h.last().as_str().to_owned()),
+        None => req.url().query_pairs().find(|(k, _)| k == "api_key").map(|(_, v)| v.into_owned())
+    }
+}
+
+/// The address of the client, looking through trusted proxies.
+///
+/// If the peer is a trusted proxy, `X-Forwarded-For` is followed from the
+/// right to the first address that is not a trusted proxy itself. Entries left
+/// of it could have been made up by the client and are ignored.
+///
+/// Peers on a Unix socket are on this host, so they are trusted like proxies.
+pub fn client_ip(req: &Request<()>) -> Option<IpAddr> {
+    let forwarded: Vec<String> = match req.header("X-Forwarded-For") {
+        Some(values) => values.iter().map(|v| v.as_str().to_owned()).collect(),
+        None => vec![]
+    };
+    let hops: Vec<&str> = forwarded.iter().flat_map(|v| v.split(',')).map(|s| s.trim()).collect();
+    // Parsed on every request, the list is short and may be reloaded.
+    let trusted: Vec<IpRange> = config::trusted_proxies().iter()
+        .filter_map(|p| p.parse().ok())
+        .collect();
+
+    address(req.peer_addr(), &hops, &trusted)
+}
+
+fn address(peer: Option<&str>, hops: &[&str], trusted: &[IpRange]) -> Option<IpAddr> {
+    match peer.and_then(parse_socket_ip) {
+        Some(peer) => Some(resolve(peer, hops, trusted)),
+        None => {
+            let (last, rest) = hops.split_last()?;
+            Some(resolve(last.parse().ok()?, rest, trusted))
+        }
+    }
+}
+
+/// Follows `hops` from `peer`. The result is canonical, so IPv4 clients count
+/// as IPv4 however they reached us.
+fn resolve(peer: IpAddr, hops: &[&str], trusted: &[IpRange]) -> IpAddr {
+    let mut client = peer.to_canonical();
+    for hop in hops.iter().rev() {
+        if !trusted.iter().any(|t| t.contains(&client)) {
+            break
+        }
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => client = ip.to_canonical(),
+            Err(_) => break
+        }
+    }
+    client
+}
+
+/// The IP of `ip:port` or `[ip]:port`.
+fn parse_socket_ip(addr: &str) -> Option<IpAddr> {
+    if let Ok(sock) = addr.parse::<std::net::SocketAddr>() {
+        return Some(sock.ip())
+    }
+    addr.parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ip_ranges() {
+        let net: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("::1")));
+
+        let single: IpRange = "192.168.0.1".parse().unwrap();
+        assert!(single.contains(&ip("192.168.0.1")));
+        assert!(!single.contains(&ip("192.168.0.2")));
+
+        let v6: IpRange = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&ip("fd12::1")));
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains(&ip("8.8.8.8")));
+
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("::ffff:11.0.0.1")));
+        let mapped: IpRange = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, net);
+        assert!(mapped.contains(&ip("10.1.2.3")));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("proxy".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn follows_forwarded_for_through_trusted_proxies() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let xff = |s: &'static str| s.split(',').map(|h| h.trim()).collect::<Vec<&str>>();
+
+        // Untrusted peers cannot pretend to be someone else.
+        assert_eq!(resolve(ip("1.2.3.4"), &xff("5.6.7.8"), &trusted), ip("1.2.3.4"));
+        assert_eq!(resolve(ip("10.0.0.1"), &xff("5.6.7.8"), &trusted), ip("5.6.7.8"));
+        assert_eq!(resolve(ip("10.0.0.1"), &xff("6.6.6.6, 5.6.7.8, 10.0.0.2"), &trusted), ip("5.6.7.8"));
+        assert_eq!(resolve(ip("10.0.0.1"), &xff("garbage"), &trusted), ip("10.0.0.1"));
+        assert_eq!(resolve(ip("10.0.0.1"), &[], &trusted), ip("10.0.0.1"));
+        // IPv4 peers of a dual-stack listener.
+        assert_eq!(resolve(ip("::ffff:10.0.0.1"), &xff("5.6.7.8"), &trusted), ip("5.6.7.8"));
+        assert_eq!(resolve(ip("::ffff:1.2.3.4"), &xff("5.6.7.8"), &trusted), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn trusts_forwarded_for_from_unix_sockets() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+
+        assert_eq!(address(Some("1.2.3.4:5678"), &["5.6.7.8"], &trusted), Some(ip("1.2.3.4")));
+        assert_eq!(address(Some("[2001:db8::1]:5678"), &[], &trusted), Some(ip("2001:db8::1")));
+        // A Unix socket has no peer address.
+        assert_eq!(address(None, &["6.6.6.6", "5.6.7.8"], &trusted), Some(ip("5.6.7.8")));
+        assert_eq!(address(None, &["6.6.6.6", "10.0.0.2"], &trusted), Some(ip("6.6.6.6")));
+        assert_eq!(address(None, &[], &trusted), None);
+        assert_eq!(address(None, &["garbage"], &trusted), None);
+    }
+}
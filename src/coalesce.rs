@@ -0,0 +1,207 @@
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}};
+use async_std::channel::{self, Sender};
+use image::ImageOutputFormat;
+use lazy_static::lazy_static;
+
+use crate::{metrics, wif_error::WifError};
+
+/// Encoded image bytes and the format they were encoded in.
+pub type Rendered = Arc<(Vec<u8>, ImageOutputFormat)>;
+
+lazy_static! {
+    pub static ref RENDERS: Coalescer<Rendered> = Coalescer::default();
+}
+
+type Waiters<T> = Vec<Sender<Result<T, WifError>>>;
+
+/// Single-flight deduplication: while a job for a key is running, further
+/// callers with the same key wait for its result instead of starting their own.
+pub struct Coalescer<T> {
+    in_flight: Mutex<HashMap<String, Waiters<T>>>
+}
+
+impl<T: Clone> Default for Coalescer<T> {
+    fn default() -> Self {
+        Coalescer {
+            in_flight: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> Result<T, WifError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, WifError>>
+    {
+        loop {
+            let waiting = {
+                let mut in_flight = self.lock()?;
+                match in_flight.get_mut(key) {
+                    Some(waiters) => {
+                        let (tx, rx) = channel::bounded(1);
+                        waiters.push(tx);
+                        Some(rx)
+                    },
+                    None => {
+                        in_flight.insert(key.to_owned(), vec![]);
+                        None
+                    }
+                }
+            };
+
+            match waiting {
+                Some(rx) => match rx.recv().await {
+                    Ok(r) => {
+                        metrics::COALESCED_REQUESTS.inc();
+                        return r
+                    },
+                    // The leading request was dropped before it finished,
+                    // so try again and possibly take over.
+                    Err(_) => continue
+                },
+                None => break
+            }
+        }
+
+        let leader = Leader { coalescer: self, key, finished: false };
+        let result = f().await;
+        leader.finish(&result);
+        result
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Waiters<T>>>, WifError> {
+        match self.in_flight.lock() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(WifError::internal_error("Request coalescing state is poisoned".to_owned()))
+        }
+    }
+}
+
+/// Removes the in-flight entry of a key again, even if the leading request is cancelled.
+struct Leader<'a, T: Clone> {
+    coalescer: &'a Coalescer<T>,
+    key: &'a str,
+    finished: bool
+}
+
+impl<T: Clone> Leader<'_, T> {
+    fn finish(mut self, result: &Result<T, WifError>) {
+        let waiters = match self.coalescer.lock() {
+            Ok(mut in_flight) => in_flight.remove(self.key),
+            Err(_) => None
+        };
+        self.finished = true;
+
+        for w in waiters.unwrap_or_default() {
+            let _ = w.try_send(result.clone());
+        }
+    }
+}
+
+impl<T: Clone> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if self.finished {
+            return
+        }
+        if let Ok(mut in_flight) = self.coalescer.lock() {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_std::task;
+    use super::*;
+
+    /// Waits until `n` callers are waiting on the leader of `key`.
+    async fn waiting(coalescer: &Coalescer<u32>, key: &str, n: usize) {
+        while coalescer.lock().unwrap().get(key).map(|w| w.len()) != Some(n) {
+            task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn shares_one_computation_between_concurrent_callers() {
+        task::block_on(async {
+            let coalescer = Arc::new(Coalescer::<u32>::default());
+            let runs = Arc::new(AtomicUsize::new(0));
+            let (open, gate) = channel::bounded::<()>(1);
+
+            let callers: Vec<_> = (0..5).map(|_| {
+                let (coalescer, runs, gate) = (coalescer.clone(), runs.clone(), gate.clone());
+                task::spawn(async move {
+                    coalescer.run("key", || async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        let _ = gate.recv().await;
+                        Ok(42)
+                    }).await
+                })
+            }).collect();
+
+            waiting(&coalescer, "key", 4).await;
+            open.send(()).await.unwrap();
+            for caller in callers {
+                assert_eq!(caller.await.unwrap(), 42);
+            }
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+            assert!(coalescer.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn passes_a_failure_on_without_keeping_it() {
+        task::block_on(async {
+            let coalescer = Arc::new(Coalescer::<u32>::default());
+            let (open, gate) = channel::bounded::<()>(1);
+
+            let leader = {
+                let coalescer = coalescer.clone();
+                task::spawn(async move {
+                    coalescer.run("key", || async move {
+                        let _ = gate.recv().await;
+                        Err(WifError::internal_error("decoding failed".to_owned()))
+                    }).await
+                })
+            };
+            waiting(&coalescer, "key", 0).await;
+            let waiter = {
+                let coalescer = coalescer.clone();
+                task::spawn(async move { coalescer.run("key", || async { Ok(1) }).await })
+            };
+            waiting(&coalescer, "key", 1).await;
+
+            open.send(()).await.unwrap();
+            assert_eq!(leader.await.unwrap_err().message, "decoding failed");
+            assert_eq!(waiter.await.unwrap_err().message, "decoding failed");
+            // The next caller computes afresh.
+            assert_eq!(coalescer.run("key", || async { Ok(7) }).await.unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn lets_a_waiter_take_over_from_a_cancelled_leader() {
+        task::block_on(async {
+            let coalescer = Arc::new(Coalescer::<u32>::default());
+
+            let leader = {
+                let coalescer = coalescer.clone();
+                task::spawn(async move {
+                    coalescer.run("key", std::future::pending::<Result<u32, WifError>>).await
+                })
+            };
+            waiting(&coalescer, "key", 0).await;
+            let waiter = {
+                let coalescer = coalescer.clone();
+                task::spawn(async move { coalescer.run("key", || async { Ok(3) }).await })
+            };
+            waiting(&coalescer, "key", 1).await;
+
+            leader.cancel().await;
+            assert_eq!(waiter.await.unwrap(), 3);
+        });
+    }
+}
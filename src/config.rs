@@ -1,76 +1,219 @@
-use std::{fs, io::Write};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration
+};
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
 use lazy_static::lazy_static;
 
+use crate::{api_keys::ApiKey, client::IpRange, listen::ListenAddr, policy::PolicyEntry, rate_limit::BucketConfig};
+
+/// Files the configuration is looked for in, in this order.
+const CONFIG_FILES: [&str; 4] = ["./config.json", "./config.toml", "./config.yaml", "./config.yml"];
+/// Environment variables starting with this override settings.
+const ENV_PREFIX: &str = "WIF_";
+
+const DEFAULT_RENDER_QUEUE: usize = 64;
+const DEFAULT_COLLECTION_PAGE_SIZE: usize = 100;
+const DEFAULT_AUTH_LABEL: &str = "Log in to see restricted images";
+const DEFAULT_AUTH_DEGRADED_SIZE: u32 = 200;
+const DEFAULT_AUTH_SESSION_TTL: u64 = 3600;
+const DEFAULT_API_KEYS_USAGE_PATH: &str = "./api_usage.db";
+/// A guess every ten seconds, after ten in a row.
+const DEFAULT_RATE_LIMIT_LOGIN: BucketConfig = BucketConfig { rate: 0.1, burst: 10.0 };
+/// Tide's own request logging is left out, the access log covers it.
+const DEFAULT_LOG_LEVEL: &str = "info,tide::log=warn";
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+static SOURCE: OnceLock<Source> = OnceLock::new();
+
 lazy_static! {
-    pub static ref CONFIG: Config = {
-        match Config::load() {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("{}", e);
-
-                let cfg = Config {
-                    ip: (127,0,0,1),
-                    port: 8000,
-                    ssl_enabled: false,
-                    ssl_key: "".to_owned(),
-                    ssl_cert: "".to_owned(),
-                    image_path: "./files".to_owned(),
-                    jpg_quality: 80,
-                    base_address: "http://localhost".to_owned(),
-                    max_area: 16777216
-                };
-
-                match create_new_config_file(&cfg) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        panic!()
-                    }
-                }
+    /// `SOURCE` with the environment applied, kept for reloading.
+    static ref RESOLVED_SOURCE: Source = SOURCE.get().cloned().unwrap_or_default().with_env(std::env::vars());
+    static ref CONFIG: RwLock<Arc<Config>> = {
+        let source = &*RESOLVED_SOURCE;
+        if source.file().is_none() && !source.no_write_default {
+            if let Err(e) = create_new_config_file(&Config::default()) {
+                eprintln!("{:?}", e);
+                panic!()
+            }
+        }
 
-                cfg
+        match Config::load(source) {
+            Ok(v) => RwLock::new(Arc::new(v)),
+            Err(e) => {
+                // Falling back to defaults would silently serve with settings nobody asked for.
+                eprintln!("{}", e);
+                std::process::exit(1)
             }
         }
     };
 }
 
-pub fn ip() -> (u8, u8, u8, u8) {
-    CONFIG.ip()
+/// Sets where the configuration is read from. Must be called before any
+/// setting is read, later calls are ignored.
+pub fn set_source(source: Source) {
+    let _ = SOURCE.set(source);
+}
+
+/// The configuration in effect. A reload swaps in a new one, so code that
+/// needs several settings to agree should read them from one handle.
+pub fn current() -> Arc<Config> {
+    match CONFIG.read() {
+        Ok(c) => c.clone(),
+        Err(e) => e.into_inner().clone()
+    }
+}
+
+/// The file the configuration was read from, if any.
+pub fn file() -> Option<PathBuf> {
+    RESOLVED_SOURCE.file()
 }
-pub fn port() -> u64 {
-    CONFIG.port()
+
+/// Reads the configuration again and swaps it in if it is valid.
+///
+/// Settings that are only read at startup keep their running values. The
+/// names of the ones that changed in the file are returned, so they can be
+/// reported as waiting for a restart.
+pub fn reload() -> Result<Vec<&'static str>, ConfigError> {
+    let mut config = Config::load(&RESOLVED_SOURCE)?;
+    let running = current();
+    let pending = config.keep_restart_only(&running);
+    match CONFIG.write() {
+        Ok(mut c) => *c = Arc::new(config),
+        Err(e) => *e.into_inner() = Arc::new(config)
+    }
+    Ok(pending)
 }
+
 // pub fn ssl_enabled() -> bool {
-//     CONFIG.ssl_enabled()
+//     current().ssl_enabled()
 // }
 // pub fn ssl_key() -> String {
-//     CONFIG.ssl_key()
+//     current().ssl_key()
 // }
 // pub fn ssl_cert() -> String {
-//     CONFIG.ssl_cert()
+//     current().ssl_cert()
 // }
 pub fn image_path() -> String {
-    CONFIG.image_path()
+    current().image_path()
 }
 pub fn jpg_quality() -> u8 {
-    CONFIG.jpg_quality()
+    current().jpg_quality()
 }
 pub fn max_area() -> u64 {
-    CONFIG.max_area()
+    current().max_area()
+}
+pub fn max_width() -> Option<u32> {
+    current().max_width()
+}
+pub fn max_height() -> Option<u32> {
+    current().max_height()
+}
+pub fn render_threads() -> usize {
+    current().render_threads()
+}
+pub fn render_queue() -> usize {
+    current().render_queue()
+}
+pub fn metadata_path() -> Option<String> {
+    current().metadata_path()
+}
+pub fn collection_page_size() -> usize {
+    current().collection_page_size()
+}
+pub fn hidden_paths() -> Vec<String> {
+    current().hidden_paths()
+}
+pub fn auth_label() -> String {
+    current().auth_label()
+}
+pub fn auth_restricted() -> Vec<String> {
+    current().auth_restricted()
+}
+pub fn auth_degraded_size() -> u32 {
+    current().auth_degraded_size()
+}
+pub fn auth_session_ttl() -> u64 {
+    current().auth_session_ttl()
+}
+pub fn auth_users() -> Vec<(String, String)> {
+    current().auth_users()
+}
+pub fn auth_login_command() -> Option<String> {
+    current().auth_login_command()
+}
+pub fn signing_keys() -> Vec<(String, String)> {
+    current().signing_keys()
+}
+pub fn signing_protected() -> Vec<String> {
+    current().signing_protected()
+}
+pub fn policies() -> Vec<PolicyEntry> {
+    current().policies()
+}
+pub fn api_keys() -> Vec<ApiKey> {
+    current().api_keys()
+}
+pub fn api_keys_database() -> Option<String> {
+    current().api_keys_database()
+}
+pub fn api_keys_usage_path() -> String {
+    current().api_keys_usage_path()
+}
+pub fn trusted_proxies() -> Vec<String> {
+    current().trusted_proxies()
+}
+pub fn rate_limit_info() -> Option<BucketConfig> {
+    current().rate_limit_info()
+}
+pub fn rate_limit_image() -> Option<BucketConfig> {
+    current().rate_limit_image()
+}
+pub fn rate_limit_login() -> Option<BucketConfig> {
+    current().rate_limit_login()
+}
+pub fn log_level() -> String {
+    current().log_level()
+}
+pub fn log_access() -> bool {
+    current().log_access()
 }
-// pub fn address() -> SocketAddrV4 {
-//     SocketAddrV4::new(Ipv4Addr::new(ip().0, ip().1, ip().2, ip().3), port())
-// }
 pub fn base_address() -> String {
-    CONFIG.base_address()
+    current().base_address()
+}
+/// The addresses to listen on: `listen`, or `ip` and `port` if it is empty.
+pub fn listen() -> Vec<ListenAddr> {
+    current().listen()
 }
-pub fn address_as_str() -> String {
-    format!("{}.{}.{}.{}:{}", ip().0, ip().1, ip().2, ip().3, port())
+pub fn metrics_listen() -> Vec<ListenAddr> {
+    current().metrics_listen()
+}
+pub fn tracing_endpoint() -> Option<String> {
+    current().tracing_endpoint()
+}
+pub fn tracing_service_name() -> String {
+    current().tracing_service_name()
+}
+pub fn shutdown_deadline() -> Duration {
+    current().shutdown_deadline()
+}
+
+/// The thread count `render.threads = 0` stands for.
+fn detected_render_threads() -> usize {
+    match std::thread::available_parallelism() {
+        Ok(n) => n.get(),
+        Err(_) => 4
+    }
 }
 
 fn create_new_config_file(config: &Config) -> Result<(), String> {
-    let mut file = match fs::File::create("./config.json") {
+    let mut file = match fs::File::create(CONFIG_FILES[0]) {
         Ok(v) => v,
         Err(e) => return Err(format!("{:?}", e))
     };
@@ -83,201 +226,473 @@ fn create_new_config_file(config: &Config) -> Result<(), String> {
 }
 
 
-#[derive(Debug)]
-pub struct Config {
-    ip: (u8, u8, u8, u8),
-    port: u64,
-    ssl_enabled: bool,
-    ssl_key: String,
-    ssl_cert: String,
-    image_path: String,
-    jpg_quality: u8,
-    base_address: String,
-    max_area: u64
+/// The syntax of a configuration file, told by its extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml
 }
 
-impl Config {
-    pub fn load() -> Result<Self, String> {
-        let raw_str = match fs::read_to_string("./config.json") {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Cannot read config file --- {:?}", e))
-        };
-
-        let config: Map<String, Value> = match serde_json::from_str(&raw_str) {
-            Ok(m) => m,
-            Err(e) => return Err(format!("Cannot parse config file --- {:?}", e))
-        };
+impl Format {
+    pub fn for_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None
+        }
+    }
 
-        let port = Self::parse_port(&config)?;
-        let ip = Self::parse_ip(&config)?;
-        let ssl_enabled = Self::parse_ssl_enabled(&config)?;
-        let ssl_key = Self::parse_ssl_key(&config)?;
-        let ssl_cert = Self::parse_ssl_cert(&config)?;
-        let image_path = Self::parse_img_path(&config)?;
-        let jpg_quality = Self::parse_jpg_quality(&config)?;
-        let base_address = Self::parse_base_address(&config)?;
-        let max_area = Self::parse_max_area(&config)?;
-
-        Ok(Config {
-            ip,
-            port,
-            ssl_enabled,
-            ssl_key,
-            ssl_cert,
-            image_path,
-            jpg_quality,
-            base_address,
-            max_area
-        })
+    fn parse(&self, s: &str) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(s).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(s).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(s).map_err(|e| e.to_string())
+        }
     }
+}
 
+/// Where the configuration comes from.
+///
+/// Settings are taken from, in increasing order of precedence: the defaults,
+/// the configuration file, `WIF_*` environment variables and command line
+/// options. The file is `--config`, else `WIF_CONFIG`, else the first of
+/// `config.json`, `config.toml`, `config.yaml` and `config.yml` in the working
+/// directory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Source {
+    pub path: Option<PathBuf>,
+    /// Settings by dotted path, e.g. `auth.degraded_size`. Later ones win.
+    pub overrides: Vec<(String, Value)>,
+    /// Do not write a default `config.json` if there is no configuration file.
+    pub no_write_default: bool
+}
 
-    // DESERIALIZE
-    fn parse_ip(e: &Map<String, Value>) -> Result<(u8, u8, u8, u8), String> {
-        if let Some(v) = e.get("ip") {
-            if let Some(arr) = v.as_array() {
-                let a;
-                let b;
-                let c;
-                let d;
-                if let Some(w) = arr.get(0) {
-                    match w.as_u64() {
-                        Some(u) => a = u as u8,
-                        None => return Err("Cannot parse IP from configuration file.".to_owned())
-                    }
-                } else {return Err("Cannot parse IP from configuration file.".to_owned())}
-                if let Some(w) = arr.get(1) {
-                    match w.as_u64() {
-                        Some(u) => b = u as u8,
-                        None => return Err("Cannot parse IP from configuration file.".to_owned())
-                    }
-                } else {return Err("Cannot parse IP from configuration file.".to_owned())}
-                if let Some(w) = arr.get(2) {
-                    match w.as_u64() {
-                        Some(u) => c = u as u8,
-                        None => return Err("Cannot parse IP from configuration file.".to_owned())
-                    }
-                } else {return Err("Cannot parse IP from configuration file.".to_owned())}
-                if let Some(w) = arr.get(3) {
-                    match w.as_u64() {
-                        Some(u) => d = u as u8,
-                        None => return Err("Cannot parse IP from configuration file.".to_owned())
+impl Source {
+    /// Adds the settings from `WIF_*` variables, below the ones already set.
+    ///
+    /// `__` separates nested settings, so `WIF_AUTH__DEGRADED_SIZE` sets
+    /// `auth.degraded_size`. Values are read as JSON if they are valid JSON and
+    /// as strings otherwise. `WIF_CONFIG` and `WIF_NO_WRITE_DEFAULT` stand in
+    /// for the command line options of the same name.
+    pub fn with_env(mut self, vars: impl Iterator<Item = (String, String)>) -> Self {
+        let mut env = vec![];
+        for (name, value) in vars.filter(|(n, _)| n.starts_with(ENV_PREFIX)) {
+            match name.as_str() {
+                "WIF_CONFIG" => {
+                    if self.path.is_none() {
+                        self.path = Some(PathBuf::from(value));
                     }
-                } else {return Err("Cannot parse IP from configuration file.".to_owned())}
-
-                return Ok((a, b, c, d))
+                },
+                "WIF_NO_WRITE_DEFAULT" => self.no_write_default |= !matches!(value.as_str(), "" | "0" | "false"),
+                _ => {
+                    let path = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+                    env.push((path, json_or_string(&value)));
+                }
             }
         }
-        Err("Cannot parse IP from Configuration file.".to_owned())
+        env.append(&mut self.overrides);
+        self.overrides = env;
+        self
     }
 
-    fn parse_port(e: &Map<String, Value>) -> Result<u64, String> {
-        if let Some(v) = e.get("port") {
-            if let Some(p) = v.as_u64() {
-                return Ok(p)
-            }
+    /// The file to read, if there is one.
+    pub fn file(&self) -> Option<PathBuf> {
+        match &self.path {
+            Some(p) => Some(p.clone()),
+            None => CONFIG_FILES.iter().map(PathBuf::from).find(|p| p.exists())
         }
-        Err("Cannot parse Port in Configuration file.".to_owned())
     }
+}
 
-    fn parse_ssl_enabled(e: &Map<String, Value>) -> Result<bool, String> {
-        if let Some(v) = e.get("ssl") {
-            if let Some(o) = v.as_object() {
-                if let Some(enabled) = o.get("enabled") {
-                    if let Some(b) = enabled.as_bool() {
-                        return Ok(b)
-                    }
-                } 
+pub fn json_or_string(s: &str) -> Value {
+    serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_owned()))
+}
+
+/// A setting that could not be read, with the path to it, e.g. `auth.degraded_size`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub path: String,
+    pub message: String
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file cannot be read or is not valid JSON, TOML or YAML.
+    Unreadable(String),
+    /// The file is well-formed, but these settings are wrong.
+    Invalid(Vec<FieldError>)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Unreadable(e) => write!(f, "Cannot read configuration file --- {}", e),
+            ConfigError::Invalid(errors) => {
+                write!(f, "Invalid configuration:")?;
+                for e in errors {
+                    write!(f, "\n  {}: {}", e.path, e.message)?;
+                }
+                Ok(())
             }
         }
+    }
+}
 
-        Err("Cannot parse SSL enabled in Config file.".to_owned())
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SslConfig {
+    pub enabled: bool,
+    pub key: String,
+    pub cert: String
+}
+
+impl Default for SslConfig {
+    fn default() -> Self {
+        SslConfig { enabled: false, key: "".to_owned(), cert: "".to_owned() }
     }
+}
 
-    fn parse_ssl_key(e: &Map<String, Value>) -> Result<String, String> {
-        if let Some(v) = e.get("ssl") {
-            if let Some(o) = v.as_object() {
-                if let Some(key) = o.get("key") {
-                    if let Some(b) = key.as_str() {
-                        return Ok(b.to_owned())
-                    }
-                } 
-            }
-        }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    pub threads: usize,
+    pub queue: usize
+}
 
-        Err("Cannot parse path to SSL Key in Config File.".to_owned())
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig { threads: 0, queue: DEFAULT_RENDER_QUEUE }
     }
+}
 
-    fn parse_ssl_cert(e: &Map<String, Value>) -> Result<String, String> {
-        if let Some(v) = e.get("ssl") {
-            if let Some(o) = v.as_object() {
-                if let Some(cert) = o.get("cert") {
-                    if let Some(b) = cert.as_str() {
-                        return Ok(b.to_owned())
-                    }
-                } 
-            }
-        }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectionConfig {
+    pub page_size: usize,
+    pub hidden: Vec<String>
+}
 
-        Err("Cannot parse path to SSL Certificate in Config File.".to_owned())
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        CollectionConfig { page_size: DEFAULT_COLLECTION_PAGE_SIZE, hidden: vec![] }
     }
+}
 
-    fn parse_img_path(e: &Map<String, Value>) -> Result<String, String> {
-        if let Some(v) = e.get("image_path") {
-            if let Some(p) = v.as_str() {
-                return Ok(p.to_owned())
-            }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub label: String,
+    pub restricted: Vec<String>,
+    pub degraded_size: u32,
+    pub session_ttl_secs: u64,
+    /// Argon2 hashes of the passwords in the PHC string format, by user name.
+    pub users: BTreeMap<String, String>,
+    pub login_command: Option<String>
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            label: DEFAULT_AUTH_LABEL.to_owned(),
+            restricted: vec![],
+            degraded_size: DEFAULT_AUTH_DEGRADED_SIZE,
+            session_ttl_secs: DEFAULT_AUTH_SESSION_TTL,
+            users: BTreeMap::new(),
+            login_command: None
         }
+    }
+}
 
-        Err("Cannot parse path to Image folder in Configuration file.".to_owned())
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    pub keys: Vec<SigningKey>,
+    pub protected: Vec<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeysConfig {
+    pub keys: Vec<ApiKey>,
+    pub database: Option<String>,
+    pub usage_path: String
+}
+
+impl Default for ApiKeysConfig {
+    fn default() -> Self {
+        ApiKeysConfig { keys: vec![], database: None, usage_path: DEFAULT_API_KEYS_USAGE_PATH.to_owned() }
     }
+}
 
-    fn parse_jpg_quality(e: &Map<String, Value>) -> Result<u8, String> {
-        if let Some(v) = e.get("jpg_quality") {
-            if let Some(p) = v.as_u64() {
-                return Ok(p as u8)
-            }
+/// `level` takes `RUST_LOG` filters, e.g. `info,tide=warn`. `RUST_LOG` itself
+/// wins over it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+    /// A JSON line on stdout for every request.
+    pub access: bool
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: DEFAULT_LOG_LEVEL.to_owned(), access: true }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Admin addresses to serve `/metrics` on instead of the public ones.
+    pub listen: Vec<ListenAddr>
+}
+
+/// On SIGTERM, requests still open are given `deadline_secs` to finish.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub deadline_secs: u64
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { deadline_secs: 30 }
+    }
+}
+
+/// Spans are sent as OTLP/HTTP JSON to `endpoint`, e.g.
+/// `http://localhost:4318/v1/traces`. Tracing is off without one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub endpoint: Option<String>,
+    pub service_name: String
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig { endpoint: None, service_name: "wif".to_owned() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub info: Option<BucketConfig>,
+    pub image: Option<BucketConfig>,
+    /// Limited by default, as every attempt is a password guess.
+    pub login: Option<BucketConfig>
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { info: None, image: None, login: Some(DEFAULT_RATE_LIMIT_LOGIN) }
+    }
+}
+
+
+/// The configuration file. Every setting has a default, so a file only needs
+/// the ones that differ.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ip: (u8, u8, u8, u8),
+    pub port: u16,
+    /// Replaces `ip` and `port` unless empty.
+    pub listen: Vec<ListenAddr>,
+    pub ssl: SslConfig,
+    pub base_address: String,
+    pub image_path: String,
+    pub metadata_path: Option<String>,
+    pub jpg_quality: u8,
+    pub max_area: u64,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub render: RenderConfig,
+    pub collection: CollectionConfig,
+    pub auth: AuthConfig,
+    pub signing: SigningConfig,
+    pub policies: Vec<PolicyEntry>,
+    pub api_keys: ApiKeysConfig,
+    pub trusted_proxies: Vec<String>,
+    pub rate_limit: RateLimitConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub tracing: TracingConfig,
+    pub shutdown: ShutdownConfig
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ip: (127, 0, 0, 1),
+            port: 8000,
+            listen: vec![],
+            ssl: SslConfig::default(),
+            base_address: "http://localhost".to_owned(),
+            image_path: "./files".to_owned(),
+            metadata_path: None,
+            jpg_quality: 80,
+            max_area: 16777216,
+            max_width: None,
+            max_height: None,
+            render: RenderConfig::default(),
+            collection: CollectionConfig::default(),
+            auth: AuthConfig::default(),
+            signing: SigningConfig::default(),
+            policies: vec![],
+            api_keys: ApiKeysConfig::default(),
+            trusted_proxies: vec![],
+            rate_limit: RateLimitConfig::default(),
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            tracing: TracingConfig::default(),
+            shutdown: ShutdownConfig::default()
         }
+    }
+}
 
-        Err("Cannot parse JPG Quality in Configuration file.".to_owned())
+impl Config {
+    /// Reads the configuration file, if there is one, and applies the overrides.
+    pub fn load(source: &Source) -> Result<Self, ConfigError> {
+        let mut raw = match source.file() {
+            Some(path) => read_file(&path)?,
+            None => Value::Object(Map::new())
+        };
+        for (path, value) in source.overrides.iter() {
+            set_setting(&mut raw, path, value.clone());
+        }
+        Self::from_value(raw)
     }
 
-    fn parse_max_area(e: &Map<String, Value>) -> Result<u64, String> {
-        if let Some(v) = e.get("max_area") {
-            if let Some(p) = v.as_u64() {
-                return Ok(p)
+    /// Reads as much of the configuration as possible, so every wrong setting
+    /// is reported at once instead of one per attempt to start the server.
+    ///
+    /// Each setting that cannot be deserialized is noted and dropped, then the
+    /// rest is tried again. Settings that do deserialize are validated.
+    fn from_value(mut raw: Value) -> Result<Self, ConfigError> {
+        let mut errors = vec![];
+        loop {
+            match serde_path_to_error::deserialize::<_, Config>(raw.clone()) {
+                Ok(config) => {
+                    errors.extend(config.validate());
+                    return if errors.is_empty() {
+                        Ok(config)
+                    } else {
+                        Err(ConfigError::Invalid(errors))
+                    }
+                },
+                Err(e) => {
+                    let segments: Vec<Segment> = e.path().iter().cloned().collect();
+                    errors.push(FieldError { path: e.path().to_string(), message: e.into_inner().to_string() });
+                    if !remove_setting(&mut raw, &segments) {
+                        return Err(ConfigError::Invalid(errors))
+                    }
+                }
             }
         }
-
-        Err("Cannot parse JPG Quality in Configuration file.".to_owned())
     }
 
-    fn parse_base_address(e: &Map<String, Value>) -> Result<String, String> {
-        if let Some(v) = e.get("base_address") {
-            if let Some(s) = v.as_str() {
-                return Ok(s.to_owned())
+    /// Checks what the types alone cannot express.
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, path: &str, message: &str| {
+            if !ok {
+                errors.push(FieldError { path: path.to_owned(), message: message.to_owned() });
+            }
+        };
+
+        check(self.port > 0, "port", "must not be 0");
+        check((1..=100).contains(&self.jpg_quality), "jpg_quality", "must be between 1 and 100");
+        check(self.max_area > 0, "max_area", "must be greater than 0");
+        check(self.max_width != Some(0), "max_width", "must be greater than 0");
+        check(self.max_height != Some(0), "max_height", "must be greater than 0");
+        check(self.render.queue > 0, "render.queue", "must be greater than 0");
+        check(self.collection.page_size > 0, "collection.page_size", "must be greater than 0");
+        check(self.auth.degraded_size > 0, "auth.degraded_size", "must be greater than 0");
+        check(self.auth.session_ttl_secs > 0, "auth.session_ttl_secs", "must be greater than 0");
+        for (i, k) in self.signing.keys.iter().enumerate() {
+            check(!k.id.is_empty(), &format!("signing.keys[{}].id", i), "must not be empty");
+            check(!k.secret.is_empty(), &format!("signing.keys[{}].secret", i), "must not be empty");
+        }
+        for (family, bucket) in [("info", &self.rate_limit.info), ("image", &self.rate_limit.image), ("login", &self.rate_limit.login)] {
+            if let Some(b) = bucket {
+                check(b.rate > 0.0 && b.burst > 0.0, &format!("rate_limit.{}", family), "needs a positive rate and burst");
             }
         }
+        if let Some(endpoint) = &self.tracing.endpoint {
+            // The exporter speaks plain HTTP only.
+            let ok = tide::http::Url::parse(endpoint).map(|u| u.scheme() == "http" && u.host_str().is_some()).unwrap_or(false);
+            check(ok, "tracing.endpoint", "must be an http:// URL");
+        }
+        for (name, hash) in &self.auth.users {
+            if let Err(e) = crate::auth::login::check_hash(hash) {
+                errors.push(FieldError {
+                    path: format!("auth.users.{}", name),
+                    message: format!("must be an Argon2 PHC string as written by `wif hash-password` --- {}", e)
+                });
+            }
+        }
+        // A bare word may be a module as well as a level, only `module=level` can be checked.
+        for (_, level) in self.log.level.split(',').filter_map(|d| d.split_once('=')) {
+            if !LOG_LEVELS.contains(&level.trim().to_lowercase().as_str()) {
+                errors.push(FieldError { path: "log.level".to_owned(), message: format!("{} is not a log level", level.trim()) });
+            }
+        }
+        for (i, p) in self.trusted_proxies.iter().enumerate() {
+            if let Err(e) = p.parse::<IpRange>() {
+                errors.push(FieldError { path: format!("trusted_proxies[{}]", i), message: e });
+            }
+        }
+        errors
+    }
 
-        Err("Cannot parse base_address in Configuration file.".to_owned())
+
+    /// Copies the settings that only take effect at startup from the running
+    /// configuration, and returns the names of the ones that differed.
+    fn keep_restart_only(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut pending = vec![];
+        keep(&mut pending, "ip", &mut self.ip, &running.ip);
+        keep(&mut pending, "port", &mut self.port, &running.port);
+        keep(&mut pending, "listen", &mut self.listen, &running.listen);
+        keep(&mut pending, "ssl", &mut self.ssl, &running.ssl);
+        keep(&mut pending, "render.threads", &mut self.render.threads, &running.render.threads);
+        keep(&mut pending, "render.queue", &mut self.render.queue, &running.render.queue);
+        keep(&mut pending, "api_keys.database", &mut self.api_keys.database, &running.api_keys.database);
+        keep(&mut pending, "api_keys.usage_path", &mut self.api_keys.usage_path, &running.api_keys.usage_path);
+        keep(&mut pending, "log.level", &mut self.log.level, &running.log.level);
+        keep(&mut pending, "metrics.listen", &mut self.metrics.listen, &running.metrics.listen);
+        pending
     }
 
 
     // GETTERS
-    pub fn ip(&self) -> (u8, u8, u8, u8) {
-        self.ip
-    }
-    pub fn port(&self) -> u64 {
-        self.port
+    pub fn listen(&self) -> Vec<ListenAddr> {
+        if self.listen.is_empty() {
+            let (a, b, c, d) = self.ip;
+            vec![ListenAddr::Tcp(std::net::SocketAddr::from(([a, b, c, d], self.port)))]
+        } else {
+            self.listen.clone()
+        }
     }
     // pub fn ssl_enabled(&self) -> bool {
-    //     self.ssl_enabled
+    //     self.ssl.enabled
     // }
     // pub fn ssl_key(&self) -> String {
-    //     self.ssl_key.clone()
+    //     self.ssl.key.clone()
     // }
     // pub fn ssl_cert(&self) -> String {
-    //     self.ssl_cert.clone()
+    //     self.ssl.cert.clone()
     // }
     pub fn image_path(&self) -> String {
         self.image_path.clone()
@@ -291,28 +706,291 @@ impl Config {
     pub fn base_address(&self) -> String {
         self.base_address.clone()
     }
+    pub fn max_width(&self) -> Option<u32> {
+        self.max_width
+    }
+    pub fn max_height(&self) -> Option<u32> {
+        self.max_height
+    }
+    /// `0`, the default, means one per CPU of the machine Wif runs on.
+    pub fn render_threads(&self) -> usize {
+        match self.render.threads {
+            0 => detected_render_threads(),
+            n => n
+        }
+    }
+    pub fn render_queue(&self) -> usize {
+        self.render.queue
+    }
+    pub fn metadata_path(&self) -> Option<String> {
+        self.metadata_path.clone()
+    }
+    pub fn collection_page_size(&self) -> usize {
+        self.collection.page_size
+    }
+    pub fn hidden_paths(&self) -> Vec<String> {
+        self.collection.hidden.iter().map(|p| p.trim_matches('/').to_owned()).collect()
+    }
+    pub fn auth_label(&self) -> String {
+        self.auth.label.clone()
+    }
+    pub fn auth_restricted(&self) -> Vec<String> {
+        self.auth.restricted.clone()
+    }
+    pub fn auth_degraded_size(&self) -> u32 {
+        self.auth.degraded_size
+    }
+    pub fn auth_session_ttl(&self) -> u64 {
+        self.auth.session_ttl_secs
+    }
+    pub fn auth_users(&self) -> Vec<(String, String)> {
+        self.auth.users.iter().map(|(u, h)| (u.clone(), h.clone())).collect()
+    }
+    pub fn auth_login_command(&self) -> Option<String> {
+        self.auth.login_command.clone()
+    }
+    pub fn signing_keys(&self) -> Vec<(String, String)> {
+        self.signing.keys.iter().map(|k| (k.id.clone(), k.secret.clone())).collect()
+    }
+    pub fn signing_protected(&self) -> Vec<String> {
+        self.signing.protected.clone()
+    }
+    pub fn policies(&self) -> Vec<PolicyEntry> {
+        self.policies.clone()
+    }
+    pub fn api_keys(&self) -> Vec<ApiKey> {
+        self.api_keys.keys.clone()
+    }
+    pub fn api_keys_database(&self) -> Option<String> {
+        self.api_keys.database.clone()
+    }
+    pub fn api_keys_usage_path(&self) -> String {
+        self.api_keys.usage_path.clone()
+    }
+    pub fn trusted_proxies(&self) -> Vec<String> {
+        self.trusted_proxies.clone()
+    }
+    pub fn rate_limit_info(&self) -> Option<BucketConfig> {
+        self.rate_limit.info
+    }
+    pub fn rate_limit_image(&self) -> Option<BucketConfig> {
+        self.rate_limit.image
+    }
+    pub fn rate_limit_login(&self) -> Option<BucketConfig> {
+        self.rate_limit.login
+    }
+    pub fn log_level(&self) -> String {
+        self.log.level.clone()
+    }
+    pub fn log_access(&self) -> bool {
+        self.log.access
+    }
+    pub fn metrics_listen(&self) -> Vec<ListenAddr> {
+        self.metrics.listen.clone()
+    }
+    pub fn tracing_endpoint(&self) -> Option<String> {
+        self.tracing.endpoint.clone()
+    }
+    pub fn tracing_service_name(&self) -> String {
+        self.tracing.service_name.clone()
+    }
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_secs(self.shutdown.deadline_secs)
+    }
 
 
     // SERIALIZE
     pub fn serialize(&self) -> String {
-        let ip_str = format!("[
-        {},
-        {},
-        {},
-        {}
-    ]", self.ip.0, self.ip.1, self.ip.2, self.ip.3);
-
-        format!("{{
-    \"ip\": {},
-    \"port\": {},
-    \"ssl\": {{
-        \"enabled\": {},
-        \"key\": \"{}\",
-        \"cert\": \"{}\"
-    }},
-    \"image_path\": \"{}\",
-    \"jpg_quality\": {},
-    \"max_area\": {}
-}}", ip_str, self.port, self.ssl_enabled, self.ssl_key, self.ssl_cert, self.image_path, self.jpg_quality, self.max_area)
+        match serde_json::to_string_pretty(self) {
+            Ok(s) => s,
+            Err(e) => panic!("Cannot serialize configuration --- {:?}", e)
+        }
+    }
+}
+
+fn keep<T: Clone + PartialEq>(pending: &mut Vec<&'static str>, name: &'static str, new: &mut T, running: &T) {
+    if new != running {
+        *new = running.clone();
+        pending.push(name);
+    }
+}
+
+fn read_file(path: &Path) -> Result<Value, ConfigError> {
+    let format = match Format::for_path(path) {
+        Some(f) => f,
+        None => return Err(ConfigError::Unreadable(format!("{:?} is not a .json, .toml or .yaml file", path)))
+    };
+    let raw_str = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => return Err(ConfigError::Unreadable(format!("{:?} --- {:?}", path, e)))
+    };
+    format.parse(&raw_str).map_err(ConfigError::Unreadable)
+}
+
+/// Sets the setting at a dotted path, creating the sections on the way.
+fn set_setting(raw: &mut Value, path: &str, value: Value) {
+    let mut current = raw;
+    for key in path.split('.') {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = match current.as_object_mut() {
+            Some(map) => map.entry(key.to_owned()).or_insert(Value::Null),
+            None => return
+        };
+    }
+    *current = value;
+}
+
+/// Drops the setting an error was found at, so the default takes its place.
+///
+/// Entries of lists cannot be dropped on their own without shifting the paths
+/// of later entries, so the whole list goes. Returns false if there is nothing
+/// left to drop.
+fn remove_setting(raw: &mut Value, path: &[Segment]) -> bool {
+    let keys: Vec<&str> = path.iter()
+        .map_while(|s| match s {
+            Segment::Map { key } => Some(key.as_str()),
+            _ => None
+        })
+        .collect();
+    let (last, parents) = match keys.split_last() {
+        Some(v) => v,
+        None => return false
+    };
+
+    let mut current = raw;
+    for key in parents {
+        current = match current.get_mut(*key) {
+            Some(v) => v,
+            None => return false
+        };
+    }
+    match current.as_object_mut() {
+        Some(map) => map.remove(*last).is_some(),
+        None => false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str, format: Format) -> Result<Config, ConfigError> {
+        Config::from_value(format.parse(s).map_err(ConfigError::Unreadable)?)
+    }
+
+    fn errors(s: &str, format: Format) -> Vec<String> {
+        match parse(s, format) {
+            Err(ConfigError::Invalid(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            other => panic!("expected invalid configuration, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn round_trips_through_serialize() {
+        let mut config = Config {
+            base_address: "https://iiif.example.org".to_owned(),
+            max_width: Some(4000),
+            ..Config::default()
+        };
+        config.auth.users.insert("alice".to_owned(), "$argon2id$v=19$m=19456,t=2,p=1$zWjkrwxAXUA2A9NS8dpcoQ$mGEmQjb7o9XlGX1/cS7b5ytkgJiX1+Qhs0B/wwkZjas".to_owned());
+        config.signing.keys.push(SigningKey { id: "k1".to_owned(), secret: "secret".to_owned() });
+        config.rate_limit.info = Some(BucketConfig { rate: 10.0, burst: 20.0 });
+        config.trusted_proxies.push("10.0.0.0/8".to_owned());
+        config.listen = vec!["[::]:8000".parse().unwrap(), "unix:/run/wif.sock".parse().unwrap()];
+
+        let reparsed = parse(&config.serialize(), Format::Json).unwrap();
+        assert_eq!(reparsed, config);
+        assert_eq!(reparsed.base_address(), "https://iiif.example.org");
+    }
+
+    #[test]
+    fn reads_every_format_with_defaults() {
+        let json = parse(r#"{ "port": 9000, "auth": { "degraded_size": 150 } }"#, Format::Json).unwrap();
+        let toml = parse("port = 9000\n[auth]\ndegraded_size = 150\n", Format::Toml).unwrap();
+        let yaml = parse("port: 9000\nauth:\n  degraded_size: 150\n", Format::Yaml).unwrap();
+
+        assert_eq!(json, toml);
+        assert_eq!(json, yaml);
+        assert_eq!(json.port, 9000);
+        assert_eq!(json.auth_label(), DEFAULT_AUTH_LABEL);
+        assert_eq!(json.max_area(), 16777216);
+    }
+
+    #[test]
+    fn reports_all_errors_with_paths() {
+        let errors = errors(r#"{
+            "max_area": "large",
+            "jpg_quality": 0,
+            "auth": { "degraded_size": -1, "labl": "typo" },
+            "signing": { "keys": [{ "id": "k1" }] },
+            "listen": ["[::]:8000", "localhost"],
+            "trusted_proxies": ["10.0.0.0/8", "proxy"],
+            "tracing": { "endpoint": "https://collector:4318/v1/traces" }
+        }"#, Format::Json);
+
+        for path in ["max_area", "jpg_quality", "auth.degraded_size", "auth.labl", "signing.keys[0]", "listen[1]", "trusted_proxies[1]", "tracing.endpoint"] {
+            assert!(errors.iter().any(|e| e == path), "{} missing from {:?}", path, errors);
+        }
+        assert!(matches!(parse("{ nope", Format::Json), Err(ConfigError::Unreadable(_))));
+    }
+
+    #[test]
+    fn keeps_settings_that_need_a_restart() {
+        let running = Config::default();
+        let mut reloaded = Config { port: 9000, jpg_quality: 90, ..Config::default() };
+        reloaded.render.threads = running.render.threads + 1;
+
+        assert_eq!(reloaded.keep_restart_only(&running), vec!["port", "render.threads"]);
+        assert_eq!(reloaded.port, running.port);
+        assert_eq!(reloaded.render_threads(), running.render_threads());
+        assert_eq!(reloaded.jpg_quality(), 90);
+    }
+
+    #[test]
+    fn detects_render_threads_when_unset() {
+        let config = Config::default();
+        assert_eq!(config.render.threads, 0);
+        assert_eq!(config.render_threads(), detected_render_threads());
+        assert!(serde_json::to_string(&config).unwrap().contains("\"threads\":0"));
+
+        let fixed = Config { render: RenderConfig { threads: 3, ..RenderConfig::default() }, ..Config::default() };
+        assert_eq!(fixed.render_threads(), 3);
+    }
+
+    #[test]
+    fn overrides_settings_from_env_and_command_line() {
+        let dir = std::env::temp_dir().join(format!("wif-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wif.toml");
+        fs::write(&path, "port = 9000\nimage_path = \"/srv/images\"\n[auth]\ndegraded_size = 150\n").unwrap();
+
+        let cli = Source {
+            path: Some(path.clone()),
+            overrides: vec![("port".to_owned(), json_or_string("8080"))],
+            no_write_default: false
+        };
+        let env = vec![
+            ("WIF_PORT", "7000"),
+            ("WIF_AUTH__DEGRADED_SIZE", "100"),
+            ("WIF_BASE_ADDRESS", "https://iiif.example.org"),
+            ("WIF_CONFIG", "/ignored.json"),
+            ("HOME", "/root")
+        ];
+        let source = cli.with_env(env.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
+        assert_eq!(source.path, Some(path.clone()));
+
+        let config = Config::load(&source).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.auth_degraded_size(), 100);
+        assert_eq!(config.base_address(), "https://iiif.example.org");
+        assert_eq!(config.image_path(), "/srv/images");
+
+        let typo = Source { path: Some(path), ..Source::default() }
+            .with_env(std::iter::once(("WIF_AUTH__DEGRADED".to_owned(), "1".to_owned())));
+        assert!(matches!(Config::load(&typo), Err(ConfigError::Invalid(_))));
+        let _ = fs::remove_dir_all(&dir);
     }
 }
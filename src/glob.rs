@@ -0,0 +1,71 @@
+/// Matches a slash separated path against a glob pattern.
+///
+/// `*` matches any characters except `/`, `**` matches any characters including `/`
+/// and `?` matches a single character except `/`.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches_from(&pattern, &path)
+}
+
+fn matches_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // `**/` may also match no directory at all.
+            if rest.first() == Some(&'/') && matches_from(&rest[1..], path) {
+                return true
+            }
+            (0..=path.len()).any(|i| matches_from(rest, &path[i..]))
+        },
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=path.len() {
+                if matches_from(rest, &path[i..]) {
+                    return true
+                }
+                if path.get(i) == Some(&'/') {
+                    break
+                }
+            }
+            false
+        },
+        Some('?') => match path.first() {
+            Some(c) if *c != '/' => matches_from(&pattern[1..], &path[1..]),
+            _ => false
+        },
+        Some(p) => match path.first() {
+            Some(c) if c == p => matches_from(&pattern[1..], &path[1..]),
+            _ => false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn matches_glob_patterns() {
+        let cases = [
+            ("private", "private", true),
+            ("private", "private/a", false),
+            ("*.tif", "scan.tif", true),
+            ("*.tif", "books/scan.tif", false),
+            ("**/*.tif", "scan.tif", true),
+            ("**/*.tif", "books/vol1/scan.tif", true),
+            ("books/*/drafts", "books/vol1/drafts", true),
+            ("books/*/drafts", "books/vol1/vol2/drafts", false),
+            ("books/**", "books/vol1/scan", true),
+            ("page?", "page1", true),
+            ("page?", "page10", false),
+            ("page?", "page/", false)
+        ];
+
+        for (pattern, path, expected) in cases.iter() {
+            assert_eq!(matches(pattern, path), *expected, "{} against {}", pattern, path);
+        }
+    }
+}
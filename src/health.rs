@@ -0,0 +1,87 @@
+use std::{fs, path::Path};
+use serde_json::{Map, Value, json};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::{config, iiif::{img_info::ImgView, quality}};
+
+/// Cargo features compiled in. Wif has none yet, the list is kept so the
+/// response does not change shape once it does.
+const FEATURES: &[&str] = &[];
+
+/// `GET /health/live`: the process is up and answering requests.
+pub async fn live(_: Request<()>) -> tide::Result<Response> {
+    json_response(StatusCode::Ok, json!({ "status": "ok" }))
+}
+
+/// `GET /health/ready`: 200 if images can be served, 503 with the failing
+/// checks otherwise.
+pub async fn ready(_: Request<()>) -> tide::Result<Response> {
+    let results = checks();
+    let ready = results.iter().all(|(_, r)| r.is_ok());
+    let checks: Map<String, Value> = results.into_iter()
+        .map(|(name, r)| (name.to_owned(), Value::String(r.err().unwrap_or_else(|| "ok".to_owned()))))
+        .collect();
+
+    if ready {
+        json_response(StatusCode::Ok, json!({ "status": "ready", "checks": checks }))
+    } else {
+        json_response(StatusCode::ServiceUnavailable, json!({ "status": "not ready", "checks": checks }))
+    }
+}
+
+/// `GET /version`
+pub async fn version(_: Request<()>) -> tide::Result<Response> {
+    json_response(StatusCode::Ok, json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": FEATURES,
+        "formats": {
+            "source": ImgView::source_extensions(),
+            "output": quality::OUTPUT_EXTENSIONS
+        }
+    }))
+}
+
+/// Everything images are resolved from, by configuration setting.
+fn checks() -> Vec<(&'static str, Result<(), String>)> {
+    let mut results = vec![("image_path", readable_dir(Path::new(&config::image_path())))];
+    if let Some(dir) = config::metadata_path() {
+        results.push(("metadata_path", readable_dir(Path::new(&dir))));
+    }
+    if let Some(db) = config::api_keys_database() {
+        results.push(("api_keys.database", readable_file(Path::new(&db))));
+    }
+    results
+}
+
+fn readable_dir(dir: &Path) -> Result<(), String> {
+    fs::read_dir(dir).map(|_| ()).map_err(|e| format!("cannot read {} --- {}", dir.display(), e))
+}
+
+fn readable_file(path: &Path) -> Result<(), String> {
+    fs::File::open(path).map(|_| ()).map_err(|e| format!("cannot read {} --- {}", path.display(), e))
+}
+
+fn json_response(status: StatusCode, body: Value) -> tide::Result<Response> {
+    let mut res = Response::new(status);
+    res.insert_header("Cache-Control", "no-store");
+    res.set_body(Body::from_json(&body)?);
+    Ok(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_directories() {
+        let dir = std::env::temp_dir().join(format!("wif-health-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(readable_dir(&dir), Ok(()));
+        fs::remove_dir(&dir).unwrap();
+
+        assert!(readable_dir(&dir).is_err());
+        assert!(readable_file(&dir.join("keys.db")).is_err());
+    }
+}
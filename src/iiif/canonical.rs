@@ -0,0 +1,63 @@
+use std::time::UNIX_EPOCH;
+use crate::{config, wif_error::WifError};
+use super::{
+    img_info::ImgView,
+    region::EPicRegion,
+    size::{EPicSize, SizeLimits},
+    rotation::EPicRotation,
+    quality::EPicQuality,
+    version::ApiVersion
+};
+
+/// The canonical form of an image request, relative to the base URI of its API version.
+pub fn image_request(img: &ImgView, region: &EPicRegion, size: &EPicSize, rotation: &EPicRotation, quality: &EPicQuality, limits: &SizeLimits, version: ApiVersion) -> Result<String, WifError> {
+    let section = region.section(img)?;
+    Ok(format!(
+        "{}/{}/{}/{}/{}",
+        img.identifier,
+        region.canonical(img)?,
+        size.canonical(&section.dimensions, limits, version)?,
+        rotation.canonical(),
+        quality.canonical()
+    ))
+}
+
+/// The key identical renderings are coalesced by.
+///
+/// Besides the canonical request it holds everything else the output depends
+/// on: the size limits `max` was resolved under, the JPEG quality, which can be
+/// reloaded, and the modification time and length of the source file.
+pub fn render_key(img: &ImgView, canonical: &str, limits: &SizeLimits) -> String {
+    let mut key = format!("{}x{}x{}/{}", limits.max_width, limits.max_height, limits.max_area, canonical);
+    if canonical.ends_with(".jpg") {
+        key.push_str(&format!("@{}", config::jpg_quality()));
+    }
+    let modified = img.modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{}#{}-{}", key, modified, img.bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use super::*;
+
+    #[test]
+    fn keys_renders_by_source_and_limits() {
+        let limits = SizeLimits::from_config();
+        let img = ImgView { modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1)), bytes: 100, ..ImgView::stub(400, 300) };
+        let key = render_key(&img, "test/full/max/0/default.png", &limits);
+        assert_eq!(key, format!("{}x{}x{}/test/full/max/0/default.png#1000000000-100", limits.max_width, limits.max_height, limits.max_area));
+
+        let replaced = ImgView { bytes: 101, ..ImgView::stub(400, 300) };
+        assert_ne!(render_key(&replaced, "test/full/max/0/default.png", &limits), key);
+        let touched = ImgView { modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2)), bytes: 100, ..ImgView::stub(400, 300) };
+        assert_ne!(render_key(&touched, "test/full/max/0/default.png", &limits), key);
+        let narrower = SizeLimits { max_width: 100, ..limits };
+        assert_ne!(render_key(&img, "test/full/max/0/default.png", &narrower), key);
+        assert!(render_key(&img, "test/full/max/0/default.jpg", &limits).contains(&format!(".jpg@{}#", config::jpg_quality())));
+    }
+}
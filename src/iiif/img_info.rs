@@ -1,11 +1,23 @@
 use image::ImageFormat;
 use lazy_static::lazy_static;
-use log::info;
-use std::{fs::File, path::Path, io::BufReader};
+use std::{collections::HashMap, fs::File, io::BufReader, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
 use png;
 use jpeg_decoder;
 
 use crate::{config, wif_error::WifError};
+use super::metadata::ImageMetadata;
+
+/// Upper bound of resolved images kept in memory. The cache starts over once it is reached.
+const MAX_CACHED_VIEWS: usize = 10_000;
+
+/// Identifiers taken by the other endpoints below `/iiif`. Images with these
+/// names right in the image folder cannot be addressed and are not served.
+pub const RESERVED_IDENTIFIERS: [&str; 4] = ["manifest", "collection", "auth", "2"];
+
+/// Characters escaped in identifiers. Most importantly `/`, so images in
+/// subfolders are addressed as `folder%2Fimage`.
+const IDENTIFIER_ENCODE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
 
 lazy_static! {
     static ref IIIF_EXTENSIONS: [(&'static str, ImageFormat); 5] = [
@@ -15,15 +27,16 @@ lazy_static! {
         ("jpg", ImageFormat::Jpeg),
         ("bmp", ImageFormat::Bmp)
     ];
+    static ref IMG_VIEWS: Mutex<HashMap<String, CachedView>> = Mutex::new(HashMap::new());
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Rect {
     pub width: u32,
     pub height: u32
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ImgSection {
     pub x: u32,
     pub y: u32,
@@ -43,35 +56,112 @@ pub struct ImgView {
     pub identifier: String,
     pub filepath: String,
     pub format: ImageFormat,
-    pub dimensions: Rect
+    pub dimensions: Rect,
+    pub metadata: ImageMetadata,
+    /// Modification time and length of the source file when it was resolved,
+    /// so derivatives of a replaced file are not mistaken for current ones.
+    pub modified: Option<SystemTime>,
+    pub bytes: u64
 }
+
+/// A resolved image together with the modification times of every file it
+/// was built from, so changes on disk can be noticed.
+struct CachedView {
+    view: Arc<ImgView>,
+    stamps: Vec<(PathBuf, Option<SystemTime>)>
+}
+
 impl ImgView {
-    pub fn for_identifier(identifier: &str) -> Result<Self, WifError> {
-        let path = format!("{}/{}", config::image_path(), identifier);
-        for (ext, f) in IIIF_EXTENSIONS.iter() {
-            let ext_path_lower = format!("{}.{}", path, ext);
-            info!("{}", ext_path_lower);
-            if Path::new(&ext_path_lower).exists() {
-                return Ok(ImgView {
-                    dimensions: Self::get_dimensions(&ext_path_lower, f)?,
-                    identifier: identifier.to_owned(),
-                    filepath: ext_path_lower,
-                    format: f.to_owned(),
-                })
+    /// Resolves an identifier as found in a URL to its source image and sidecar
+    /// metadata. Results are cached until the source or one of its sidecar files changes.
+    pub fn for_identifier(identifier: &str) -> Result<Arc<Self>, WifError> {
+        let rel_path = decode_identifier(identifier)?;
+        if RESERVED_IDENTIFIERS.contains(&rel_path.as_str()) {
+            return Err(WifError::not_found(format!("{} is reserved, rename the image to serve it", identifier)))
+        }
+        if let Some(view) = Self::cached(&rel_path) {
+            return Ok(view)
+        }
+
+        let view = Arc::new(Self::resolve(&rel_path)?);
+        let mut stamps = vec![(PathBuf::from(&view.filepath), modified(Path::new(&view.filepath)))];
+        for p in ImageMetadata::sidecar_candidates(&rel_path) {
+            let m = modified(&p);
+            stamps.push((p, m));
+        }
+
+        if let Ok(mut cache) = IMG_VIEWS.lock() {
+            if cache.len() >= MAX_CACHED_VIEWS {
+                cache.clear();
             }
+            cache.insert(rel_path, CachedView { view: view.clone(), stamps });
+        }
+        Ok(view)
+    }
+
+    /// A view of a PNG that need not exist, for tests.
+    #[cfg(test)]
+    pub fn stub(width: u32, height: u32) -> Self {
+        ImgView {
+            identifier: "test".to_owned(),
+            filepath: "test.png".to_owned(),
+            format: ImageFormat::Png,
+            dimensions: Rect { width, height },
+            metadata: ImageMetadata::default(),
+            modified: None,
+            bytes: 0
+        }
+    }
+
+    /// Forgets all resolved images, e.g. because the image folder was reconfigured.
+    pub fn forget_all() {
+        if let Ok(mut cache) = IMG_VIEWS.lock() {
+            cache.clear();
+        }
+    }
+
+    fn cached(rel_path: &str) -> Option<Arc<Self>> {
+        let cache = IMG_VIEWS.lock().ok()?;
+        let cached = cache.get(rel_path)?;
+        if cached.stamps.iter().all(|(p, m)| modified(p) == *m) {
+            Some(cached.view.clone())
+        } else {
+            None
+        }
+    }
 
-            let ext_path_upper = format!("{}.{}", path, ext.to_uppercase());
-            if Path::new(&ext_path_upper).exists() {
-                return Ok(ImgView {
-                    dimensions: Self::get_dimensions(&ext_path_lower, f)?,
-                    identifier: identifier.to_owned(),
-                    filepath: ext_path_upper,
-                    format: f.to_owned()
-                })
+    fn resolve(rel_path: &str) -> Result<Self, WifError> {
+        let path = format!("{}/{}", config::image_path(), rel_path);
+        for (ext, f) in IIIF_EXTENSIONS.iter() {
+            for candidate in [format!("{}.{}", path, ext), format!("{}.{}", path, ext.to_uppercase())] {
+                if let Ok(meta) = std::fs::metadata(&candidate) {
+                    return Ok(ImgView {
+                        dimensions: Self::get_dimensions(&candidate, f)?,
+                        identifier: encode_identifier(rel_path),
+                        filepath: candidate,
+                        format: f.to_owned(),
+                        metadata: ImageMetadata::load(rel_path),
+                        modified: meta.modified().ok(),
+                        bytes: meta.len()
+                    })
+                }
             }
         }
 
-        Err(WifError::not_found(format!("{} not found", identifier)))
+        Err(WifError::not_found(format!("{} not found", encode_identifier(rel_path))))
+    }
+
+    /// Whether a file is a source image wif can serve, judging by its extension.
+    pub fn is_source(path: &Path) -> bool {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => IIIF_EXTENSIONS.iter().any(|(e, _)| e.eq_ignore_ascii_case(ext)),
+            None => false
+        }
+    }
+
+    /// File extensions of the source images wif can serve.
+    pub fn source_extensions() -> Vec<&'static str> {
+        IIIF_EXTENSIONS.iter().map(|(e, _)| *e).collect()
     }
 
     fn get_dimensions(path: &str, format: &ImageFormat) -> Result<Rect, WifError> {
@@ -128,3 +218,31 @@ impl ImgView {
         self.dimensions.height
     }
 }
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Decodes an identifier or folder name from a URL into a path relative to the
+/// image folder, rejecting anything that could point outside of it.
+pub fn decode_identifier(raw: &str) -> Result<String, WifError> {
+    let decoded = match percent_decode_str(raw).decode_utf8() {
+        Ok(s) => s.into_owned(),
+        Err(_) => return Err(WifError::bad_request("Identifier is not valid UTF-8".to_owned()))
+    };
+
+    let invalid = decoded.is_empty()
+        || decoded.contains('\\')
+        || decoded.contains('\0')
+        || decoded.split('/').any(|c| c.is_empty() || c == "." || c == "..");
+    if invalid {
+        return Err(WifError::bad_request(format!("Invalid identifier {}", raw)))
+    }
+
+    Ok(decoded)
+}
+
+/// The URL form of a path relative to the image folder.
+pub fn encode_identifier(rel_path: &str) -> String {
+    utf8_percent_encode(rel_path, IDENTIFIER_ENCODE).to_string()
+}
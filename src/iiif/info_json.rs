@@ -1,63 +1,326 @@
 use serde::{Serialize};
+use serde_json::{Value, json};
 use crate::wif_error::WifError;
-use crate::config;
-use super::img_info::ImgView;
+use crate::{auth, presentation::language_map};
+use super::{
+    img_info::{ImgView, Rect},
+    metadata::LinkedResource,
+    size::SizeLimits,
+    version::ApiVersion
+};
+
+/// Edge length of the tiles advertised in info.json.
+const TILE_SIZE: u32 = 512;
 
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct IIIFInfo {
+    #[serde(rename = "@context")]
+    context: Value,
     id: String,
-    protocol: String,
-    profile: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    protocol: &'static str,
+    profile: &'static str,
     width: u32,
     height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_height: Option<u32>,
     max_area: u64,
-    preferred_formats: Vec<String>,
-    extra_features: Vec<String>,
-    extra_qualities: Vec<String>
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sizes: Vec<SizeInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tiles: Vec<TileInfo>,
+    preferred_formats: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rights: Option<String>,
+    /// The sidecar `attribution`, shaped like a Presentation API 3.0 `requiredStatement`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    required_statement: Option<Value>,
+    extra_qualities: Vec<&'static str>,
+    extra_formats: Vec<&'static str>,
+    extra_features: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    part_of: Vec<LinkedResource>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    see_also: Vec<LinkedResource>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    service: Vec<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    logo: Vec<LinkedResource>
 }
 
 impl IIIFInfo {
-    pub fn for_img(img: &ImgView) -> Result<String, WifError> {
-        // let dimensions = Self::get_img_info(&img.filepath)?;
+    /// info.json as seen by a caller allowed to request up to `limits`.
+    pub fn for_img(img: &ImgView, limits: &SizeLimits) -> Result<String, WifError> {
+        let (max_width, max_height) = limits.info_dimensions();
+        let metadata = img.metadata.clone();
+
+        // Restricted images announce how to log in, which needs the auth context next to ours.
+        let mut service = metadata.service;
+        let context = if auth::is_restricted(img) {
+            service.insert(0, auth::probe_service(img));
+            json!([auth::AUTH_CONTEXT, ApiVersion::V3.context()])
+        } else {
+            Value::String(ApiVersion::V3.context().to_owned())
+        };
 
         let info = IIIFInfo {
-            id: format!("{}/iiif/{}", config::base_address(), &img.identifier),
-            protocol: "http://iiif.io/api/image".to_owned(),
-            profile: "level1".to_owned(),
-            width: img.dimensions.width,
-            height: img.dimensions.height,
-            max_area: config::max_area(),
-            preferred_formats: vec![
-                "tga".to_owned(),
-                "png".to_owned(),
-                "jpeg".to_owned(),
-                "ico".to_owned(),
-                "bmp".to_owned()
-            ],
+            context,
+            id: format!("{}/{}", ApiVersion::V3.base_uri(), &img.identifier),
+            kind: "ImageService3",
+            protocol: "http://iiif.io/api/image",
+            profile: "level1",
+            width: img.width(),
+            height: img.height(),
+            max_width,
+            max_height,
+            max_area: limits.max_area,
+            sizes: sizes(img, limits),
+            tiles: tiles(img),
+            preferred_formats: vec!["png", "jpg"],
+            rights: metadata.rights,
+            required_statement: metadata.attribution.map(|a| json!({
+                "label": { "en": ["Attribution"] },
+                "value": language_map(a)
+            })),
+            extra_qualities: vec!["color", "gray", "bitonal"],
+            extra_formats: vec!["png", "tga", "bmp", "ico"],
             extra_features: vec![
-                "baseUriRedirect".to_owned(),
-                "rotationBy90s".to_owned(),
-                "cors".to_owned(),
-                "mirroring".to_owned(),
-                "regionByPct".to_owned(),
-                "regionByPx".to_owned(),
-                "regionSquare".to_owned(),
-                "sizeByH".to_owned(),
-                "sizeByPct".to_owned(),
-                "sizeByW".to_owned(),
-                "sizeUpscaling".to_owned()
+                "baseUriRedirect",
+                "canonicalLinkHeader",
+                "cors",
+                "jsonldMediaType",
+                "mirroring",
+                "regionByPct",
+                "regionByPx",
+                "regionSquare",
+                "rotationBy90s",
+                "sizeByConfinedWh",
+                "sizeByH",
+                "sizeByPct",
+                "sizeByW",
+                "sizeByWh",
+                "sizeUpscaling"
             ],
-            extra_qualities: vec![
-                "default".to_owned(),
-                "bitonal".to_owned(),
-                "gray".to_owned()
-            ]
+            part_of: metadata.part_of,
+            see_also: metadata.see_also,
+            service,
+            logo: metadata.logo
+        };
+
+        to_json(&info)
+    }
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct SizeInfo {
+    width: u32,
+    height: u32
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileInfo {
+    width: u32,
+    scale_factors: Vec<u32>
+}
+
+/// Downscaled versions of the image by powers of two, smallest first,
+/// as far as they are allowed by the size limits.
+fn sizes(img: &ImgView, limits: &SizeLimits) -> Vec<SizeInfo> {
+    let mut sizes = vec![];
+    for f in scale_factors(img) {
+        let size = Rect {
+            width: img.width().div_ceil(f),
+            height: img.height().div_ceil(f)
+        };
+        if limits.allows(&size) {
+            sizes.push(SizeInfo { width: size.width, height: size.height });
+        }
+    }
+    sizes.reverse();
+    sizes
+}
+
+fn tiles(img: &ImgView) -> Vec<TileInfo> {
+    vec![TileInfo {
+        width: TILE_SIZE,
+        scale_factors: scale_factors(img)
+    }]
+}
+
+/// Powers of two up to the first one at which the whole image fits into a single tile.
+fn scale_factors(img: &ImgView) -> Vec<u32> {
+    let longest = img.width().max(img.height());
+    let mut factors = vec![1];
+    let mut f = 1;
+    while longest.div_ceil(f) > TILE_SIZE {
+        f *= 2;
+        factors.push(f);
+    }
+    factors
+}
+
+
+/// info.json of the Image API 2.1 compatibility endpoint.
+#[derive(Debug, Serialize)]
+pub struct IIIFInfo2 {
+    #[serde(rename = "@context")]
+    context: &'static str,
+    #[serde(rename = "@id")]
+    id: String,
+    protocol: &'static str,
+    width: u32,
+    height: u32,
+    profile: (&'static str, ProfileDescription2),
+    sizes: Vec<SizeInfo>,
+    tiles: Vec<TileInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribution: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    license: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logo: Option<String>,
+    #[serde(rename = "seeAlso", skip_serializing_if = "Vec::is_empty")]
+    see_also: Vec<LinkedResource2>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    service: Vec<Value>
+}
+
+/// A `seeAlso` link in the 2.1 shape, with `@id` instead of `id`.
+#[derive(Debug, Serialize)]
+struct LinkedResource2 {
+    #[serde(rename = "@id")]
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileDescription2 {
+    formats: Vec<&'static str>,
+    qualities: Vec<&'static str>,
+    supports: Vec<&'static str>,
+    max_area: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_height: Option<u32>
+}
+
+impl IIIFInfo2 {
+    pub fn for_img(img: &ImgView, limits: &SizeLimits) -> Result<String, WifError> {
+        let (max_width, max_height) = limits.info_dimensions();
+        let metadata = img.metadata.clone();
+
+        let info = IIIFInfo2 {
+            context: ApiVersion::V2.context(),
+            id: format!("{}/{}", ApiVersion::V2.base_uri(), &img.identifier),
+            protocol: "http://iiif.io/api/image",
+            width: img.width(),
+            height: img.height(),
+            profile: (
+                "http://iiif.io/api/image/2/level1.json",
+                ProfileDescription2 {
+                    formats: vec!["jpg", "png", "tga", "bmp", "ico"],
+                    qualities: vec!["default", "color", "gray", "bitonal"],
+                    supports: vec![
+                        "baseUriRedirect",
+                        "cors",
+                        "jsonldMediaType",
+                        "mirroring",
+                        "regionByPct",
+                        "regionByPx",
+                        "regionSquare",
+                        "rotationBy90s",
+                        "sizeAboveFull",
+                        "sizeByConfinedWh",
+                        "sizeByDistortedWh",
+                        "sizeByH",
+                        "sizeByPct",
+                        "sizeByW",
+                        "sizeByWh"
+                    ],
+                    max_area: limits.max_area,
+                    max_width,
+                    max_height
+                }
+            ),
+            sizes: sizes(img, limits),
+            tiles: tiles(img),
+            attribution: metadata.attribution,
+            license: metadata.rights,
+            logo: metadata.logo.into_iter().next().map(|l| l.id),
+            see_also: metadata.see_also.into_iter()
+                .map(|s| LinkedResource2 { id: s.id, format: s.format, profile: s.profile })
+                .collect(),
+            service: metadata.service
         };
 
-        Ok(format!("{{\"@context\":\"http://iiif.io/api/image/3/context.json\",{}}}", info.jsonify()))
+        to_json(&info)
     }
+}
+
+fn to_json<T: Serialize>(info: &T) -> Result<String, WifError> {
+    match serde_json::to_string(info) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(WifError::internal_error(format!("Cannot serialize info.json --- {:?}", e)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iiif::img_info::encode_identifier;
+
+    fn info(img: &ImgView, version: ApiVersion) -> Value {
+        let limits = SizeLimits { max_width: 2000, max_height: 2000, max_area: 4_000_000 };
+        let json = match version {
+            ApiVersion::V3 => IIIFInfo::for_img(img, &limits),
+            ApiVersion::V2 => IIIFInfo2::for_img(img, &limits)
+        };
+        serde_json::from_str(&json.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn carries_the_attribution_in_both_versions() {
+        let mut img = ImgView::stub(1000, 800);
+        img.metadata.attribution = Some(json!("Provided by the archive"));
+
+        let v3 = info(&img, ApiVersion::V3);
+        assert_eq!(v3["requiredStatement"], json!({
+            "label": { "en": ["Attribution"] },
+            "value": { "none": ["Provided by the archive"] }
+        }));
+        assert!(v3.get("attribution").is_none());
+        assert_eq!(info(&img, ApiVersion::V2)["attribution"], json!("Provided by the archive"));
+
+        img.metadata.attribution = Some(json!({ "de": ["Bereitgestellt vom Archiv"] }));
+        assert_eq!(info(&img, ApiVersion::V3)["requiredStatement"]["value"], json!({ "de": ["Bereitgestellt vom Archiv"] }));
+
+        img.metadata.attribution = None;
+        assert!(info(&img, ApiVersion::V3).get("requiredStatement").is_none());
+        assert!(info(&img, ApiVersion::V2).get("attribution").is_none());
+    }
+
+    #[test]
+    fn encodes_awkward_identifiers() {
+        let mut img = ImgView::stub(1000, 800);
+        img.identifier = encode_identifier("book/a \"b\"");
+        img.metadata.attribution = Some(json!("Say \"thanks\""));
 
-    fn jsonify(&self) -> String {
-        format!("\"id\":\"{}\",\"type\":\"ImageService3\",\"protocol\":\"{}\",\"profile\":\"{}\",\"width\":{},\"height\":{},\"maxArea\":{},\"preferredFormats\":{:?},\"extraFeatures\":{:?},\"extraQualities\":{:?}", self.id, self.protocol,self.profile,self.width,self.height,self.max_area,self.preferred_formats,self.extra_features,self.extra_qualities)
+        let v3 = info(&img, ApiVersion::V3);
+        assert!(v3["id"].as_str().unwrap().ends_with("/iiif/book%2Fa%20%22b%22"));
+        assert_eq!(v3["requiredStatement"]["value"], json!({ "none": ["Say \"thanks\""] }));
+        let v2 = info(&img, ApiVersion::V2);
+        assert!(v2["@id"].as_str().unwrap().ends_with("/iiif/2/book%2Fa%20%22b%22"));
     }
 }
@@ -0,0 +1,183 @@
+use std::{fs, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{config, policy::Policy};
+
+const SIDECAR_EXTENSIONS: [&str; 3] = ["json", "yaml", "yml"];
+
+/// A linked resource such as a `partOf` manifest, a `seeAlso` description or a logo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkedResource {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>
+}
+
+/// Per-image properties of info.json, read from an optional sidecar file
+/// `<identifier>.json`, `.yaml` or `.yml`.
+///
+/// Sidecars in the configured metadata directory take precedence over
+/// sidecars next to the source image.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ImageMetadata {
+    pub rights: Option<String>,
+    /// A string or a language map, e.g. `{"en": ["Provided by ..."]}`.
+    pub attribution: Option<Value>,
+    pub part_of: Vec<LinkedResource>,
+    pub see_also: Vec<LinkedResource>,
+    /// Services are passed through as they are, so any service description can be linked.
+    pub service: Vec<Value>,
+    pub logo: Vec<LinkedResource>,
+    /// Overrides the policies from the configuration file for this image.
+    pub policy: Option<Policy>
+}
+
+impl ImageMetadata {
+    /// All paths a sidecar for this identifier may be found at, in order of precedence.
+    pub fn sidecar_candidates(identifier: &str) -> Vec<PathBuf> {
+        let mut dirs = vec![];
+        if let Some(d) = config::metadata_path() {
+            dirs.push(PathBuf::from(d));
+        }
+        dirs.push(PathBuf::from(config::image_path()));
+
+        sidecar_paths(&dirs, identifier)
+    }
+
+    /// Loads the first sidecar found for the identifier.
+    pub fn load(identifier: &str) -> Self {
+        match Self::sidecar_candidates(identifier).into_iter().find(|p| p.exists()) {
+            Some(p) => Self::from_file(&p),
+            None => ImageMetadata::default()
+        }
+    }
+
+    /// A sidecar that cannot be read or is invalid may have been meant to
+    /// restrict the image, so it is logged and the image is denied to everyone
+    /// until the file is fixed.
+    fn from_file(path: &Path) -> Self {
+        match Self::read(path) {
+            Ok(m) => m,
+            Err(e) => {
+                log::error!("Denying access to the image of metadata file {:?} until it is fixed --- {}", path, e);
+                ImageMetadata { policy: Some(Policy::deny_all()), ..ImageMetadata::default() }
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let metadata: ImageMetadata = read_sidecar(path)?;
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+
+        if let Some(r) = &self.rights {
+            if !is_http_uri(r) {
+                errors.push(format!("rights: {:?} is not an http(s) URI", r));
+            }
+        }
+        if let Some(a) = &self.attribution {
+            if !(a.is_string() || a.is_object()) {
+                errors.push("attribution: must be a string or a language map".to_owned());
+            }
+        }
+
+        let links = [("partOf", &self.part_of), ("seeAlso", &self.see_also), ("logo", &self.logo)];
+        for (name, resources) in links.iter() {
+            for (i, r) in resources.iter().enumerate() {
+                if !is_http_uri(&r.id) {
+                    errors.push(format!("{}[{}].id: {:?} is not an http(s) URI", name, i, r.id));
+                }
+                if r.kind.is_empty() {
+                    errors.push(format!("{}[{}].type: must not be empty", name, i));
+                }
+            }
+        }
+
+        for (i, s) in self.service.iter().enumerate() {
+            let id = s.get("id").or_else(|| s.get("@id")).and_then(|v| v.as_str());
+            let kind = s.get("type").or_else(|| s.get("@type")).and_then(|v| v.as_str());
+            if !id.map(is_http_uri).unwrap_or(false) {
+                errors.push(format!("service[{}].id: missing or not an http(s) URI", i));
+            }
+            if kind.is_none() {
+                errors.push(format!("service[{}].type: missing", i));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// Paths `<stem>.json`, `.yaml` and `.yml` in each of the folders, in order of precedence.
+pub fn sidecar_paths(dirs: &[PathBuf], stem: &str) -> Vec<PathBuf> {
+    dirs.iter()
+        .flat_map(|d| SIDECAR_EXTENSIONS.iter().map(move |ext| d.join(format!("{}.{}", stem, ext))))
+        .collect()
+}
+
+/// Reads a JSON or YAML sidecar file, depending on its extension.
+pub fn read_sidecar<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let raw = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("{:?}", e))
+    };
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&raw).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&raw).map_err(|e| e.to_string())
+    }
+}
+
+pub fn is_http_uri(s: &str) -> bool {
+    (s.starts_with("http://") || s.starts_with("https://")) && !s.contains(char::is_whitespace)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{ClientClass, Rule};
+
+    fn sidecar(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wif-sidecar-{}-{}.json", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn denies_images_whose_sidecar_is_invalid() {
+        let valid = sidecar("valid", r#"{ "rights": "https://creativecommons.org/licenses/by/4.0/", "policy": { "anonymous": { "max_width": 800 } } }"#);
+        let metadata = ImageMetadata::from_file(&valid);
+        assert_eq!(metadata.policy.and_then(|p| p.anonymous).and_then(|r| r.max_width), Some(800));
+
+        let bad_rights = sidecar("bad-rights", r#"{ "rights": "CC-BY", "policy": { "anonymous": { "max_width": 800 } } }"#);
+        let misspelt = sidecar("misspelt", r#"{ "polcy": { "anonymous": { "deny": true } } }"#);
+        let broken = sidecar("broken", r#"{ "policy": "#);
+        for path in [&bad_rights, &misspelt, &broken] {
+            let policy = ImageMetadata::from_file(path).policy.unwrap();
+            for class in [ClientClass::Anonymous, ClientClass::ApiKey, ClientClass::Authenticated] {
+                assert!(Rule::check_access(policy.rule(class)).is_err(), "{:?} for {:?}", path, class);
+            }
+        }
+
+        for path in [valid, bad_rights, misspelt, broken] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
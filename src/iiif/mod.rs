@@ -3,4 +3,8 @@ pub mod region;
 pub mod size;
 pub mod rotation;
 pub mod quality;
-pub mod info_json;
\ No newline at end of file
+pub mod info_json;
+pub mod canonical;
+pub mod version;
+pub mod negotiate;
+pub mod metadata;
\ No newline at end of file
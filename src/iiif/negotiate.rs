@@ -0,0 +1,145 @@
+use super::version::ApiVersion;
+
+const JSON: &str = "application/json";
+const JSON_LD: &str = "application/ld+json";
+
+/// One entry of an `Accept` header.
+#[derive(Debug)]
+struct MediaRange {
+    essence: String,
+    profiles: Vec<String>,
+    q: f32
+}
+
+/// Picks the info.json variant for an `Accept` header: the API version whose
+/// context was asked for, and the content type to answer with.
+///
+/// `application/ld+json` is answered with the context as `profile` parameter.
+/// Requests that accept nothing we serve get plain JSON in the default version.
+pub fn info_json(accept: Option<&str>, default: ApiVersion) -> (ApiVersion, String) {
+    let fallback = (default, JSON.to_owned());
+    let accept = match accept {
+        Some(a) => a,
+        None => return fallback
+    };
+
+    let mut ranges = parse_accept(accept);
+    // Stable sort, so equally preferred ranges keep the client's order.
+    ranges.sort_by(|a, b| b.q.partial_cmp(&a.q).unwrap_or(std::cmp::Ordering::Equal));
+
+    for range in ranges.iter().filter(|r| r.q > 0.0) {
+        match range.essence.as_str() {
+            JSON_LD => {
+                if range.profiles.is_empty() {
+                    return (default, ld_json(default))
+                }
+                for version in [default, ApiVersion::V3, ApiVersion::V2].iter() {
+                    if range.profiles.iter().any(|p| p == version.context()) {
+                        return (*version, ld_json(*version))
+                    }
+                }
+            },
+            JSON | "application/*" | "*/*" => return fallback,
+            _ => ()
+        }
+    }
+
+    fallback
+}
+
+fn ld_json(version: ApiVersion) -> String {
+    format!("{};profile=\"{}\"", JSON_LD, version.context())
+}
+
+fn parse_accept(header: &str) -> Vec<MediaRange> {
+    let mut ranges = vec![];
+    for entry in split_unquoted(header, ',') {
+        let mut params = split_unquoted(&entry, ';').into_iter();
+        let essence = match params.next() {
+            Some(e) if !e.trim().is_empty() => e.trim().to_lowercase(),
+            _ => continue
+        };
+
+        let mut range = MediaRange {
+            essence,
+            profiles: vec![],
+            q: 1.0
+        };
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((n, v)) => (n.trim().to_lowercase(), v.trim().trim_matches('"').to_owned()),
+                None => continue
+            };
+            match name.as_str() {
+                "q" => range.q = value.parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0),
+                // The profile parameter may hold several space separated URIs.
+                "profile" => range.profiles = value.split_whitespace().map(|p| p.to_owned()).collect(),
+                _ => ()
+            }
+        }
+        ranges.push(range);
+    }
+    ranges
+}
+
+/// Splits at `sep`, except inside double quoted strings.
+fn split_unquoted(s: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in s.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if c == sep && !quoted {
+            parts.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_info_json() {
+        let v2_ld = ld_json(ApiVersion::V2);
+        let v3_ld = ld_json(ApiVersion::V3);
+        let v2_profile = format!("application/ld+json;profile=\"{}\"", ApiVersion::V2.context());
+        let v3_profile = format!("application/ld+json;profile=\"{}\"", ApiVersion::V3.context());
+        let cases: Vec<(Option<String>, (ApiVersion, &str))> = vec![
+            (None, (ApiVersion::V3, JSON)),
+            (Some("".to_owned()), (ApiVersion::V3, JSON)),
+            (Some("*/*".to_owned()), (ApiVersion::V3, JSON)),
+            (Some("application/*".to_owned()), (ApiVersion::V3, JSON)),
+            (Some("application/json".to_owned()), (ApiVersion::V3, JSON)),
+            (Some("text/html, image/png".to_owned()), (ApiVersion::V3, JSON)),
+            (Some("application/ld+json".to_owned()), (ApiVersion::V3, &v3_ld)),
+            (Some(v2_profile.clone()), (ApiVersion::V2, &v2_ld)),
+            (Some(v3_profile.clone()), (ApiVersion::V3, &v3_ld)),
+            (Some(format!("application/ld+json;profile=\"http://example.org/other {}\"", ApiVersion::V2.context())), (ApiVersion::V2, &v2_ld)),
+            (Some("application/ld+json;profile=\"http://example.org/other\"".to_owned()), (ApiVersion::V3, JSON)),
+            // The higher q-value wins, whatever the order.
+            (Some(format!("application/json;q=0.5, {};q=0.9", v2_profile)), (ApiVersion::V2, &v2_ld)),
+            (Some(format!("{};q=0.5, application/json", v2_profile)), (ApiVersion::V3, JSON)),
+            (Some(format!("*/*;q=0.1, {}", v2_profile)), (ApiVersion::V2, &v2_ld)),
+            // Equal q-values keep the client's order.
+            (Some(format!("{}, {}", v2_profile, v3_profile)), (ApiVersion::V2, &v2_ld)),
+            // q=0 means not acceptable, malformed q-values count as 0.
+            (Some(format!("{};q=0", v2_profile)), (ApiVersion::V3, JSON)),
+            (Some(format!("{};q=high", v2_profile)), (ApiVersion::V3, JSON)),
+            (Some("APPLICATION/LD+JSON".to_owned()), (ApiVersion::V3, &v3_ld))
+        ];
+
+        for (accept, (version, content_type)) in cases {
+            assert_eq!(info_json(accept.as_deref(), ApiVersion::V3), (version, content_type.to_owned()), "Accept: {:?}", accept);
+        }
+        assert_eq!(info_json(Some("application/ld+json"), ApiVersion::V2), (ApiVersion::V2, v2_ld.clone()));
+        assert_eq!(info_json(Some("*/*"), ApiVersion::V2), (ApiVersion::V2, JSON.to_owned()));
+    }
+}
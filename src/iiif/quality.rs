@@ -39,6 +39,38 @@ pub fn mutate_image_quality(quality: &mut EPicQuality, img: &mut DynamicImage) -
     Ok((buf, format))
 }
 
+impl EPicQuality {
+    pub fn format(&self) -> &ImageOutputFormat {
+        match self {
+            EPicQuality::Color(f) | EPicQuality::Gray(f) | EPicQuality::Bitonal(f) | EPicQuality::Default(f) => f
+        }
+    }
+
+    /// Color is the default quality of this server, so both are canonically `default`.
+    pub fn canonical(&self) -> String {
+        let quality = match self {
+            EPicQuality::Color(_) | EPicQuality::Default(_) => "default",
+            EPicQuality::Gray(_) => "gray",
+            EPicQuality::Bitonal(_) => "bitonal"
+        };
+        format!("{}.{}", quality, format_extension(self.format()))
+    }
+}
+
+/// Extensions of the output formats, as accepted after the quality.
+pub const OUTPUT_EXTENSIONS: [&str; 5] = ["jpg", "png", "bmp", "ico", "tga"];
+
+pub fn format_extension(format: &ImageOutputFormat) -> &'static str {
+    match format {
+        ImageOutputFormat::Jpeg(_) => "jpg",
+        ImageOutputFormat::Png => "png",
+        ImageOutputFormat::Bmp => "bmp",
+        ImageOutputFormat::Ico => "ico",
+        ImageOutputFormat::Tga => "tga",
+        _ => "bin"
+    }
+}
+
 impl FromStr for EPicQuality {
     type Err = WifError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -54,14 +86,14 @@ impl FromStr for EPicQuality {
             };
 
             match parts[0] {
-                "color" => return Ok(EPicQuality::Color(format)),
-                "gray" => return Ok(EPicQuality::Gray(format)),
-                "bitonal" => return Ok(EPicQuality::Bitonal(format)),
-                "default" => return Ok(EPicQuality::Default(format)),
-                _ => return Err(WifError::bad_request("Cannot parse quality".to_owned()))
+                "color" => Ok(EPicQuality::Color(format)),
+                "gray" => Ok(EPicQuality::Gray(format)),
+                "bitonal" => Ok(EPicQuality::Bitonal(format)),
+                "default" => Ok(EPicQuality::Default(format)),
+                _ => Err(WifError::bad_request("Cannot parse quality".to_owned()))
             }
         } else {
-            return Err(WifError::bad_request("Cannot parse quality and/or format".to_owned()))
+            Err(WifError::bad_request("Cannot parse quality and/or format".to_owned()))
         }
     }
 }
@@ -36,7 +36,7 @@ impl FromStr for EPicRegion {
                 return Err(WifError::bad_request("Request string must not contain more than x,y,w,h parameters.".to_owned()))
             }
 
-            x = match p.get(0) {
+            x = match p.first() {
                 Some(v) => match v.parse::<f32>() {
                     Ok(f) => f,
                     Err(_) => return Err(WifError::bad_request("Request string must contain parameters for coordinates, width and height in the format x,y,w,h.".to_owned()))
@@ -75,7 +75,7 @@ impl FromStr for EPicRegion {
 
             Ok(EPicRegion::RegPerc{x, y, w, h})
         } else {
-            p = match pct.get(0) {
+            p = match pct.first() {
                 Some(v) => v.split(',').collect::<Vec<&str>>(),
                 None => return Err(WifError::bad_request("Request string must contain parameters for coordinates, width and height in the format x,y,w,h.".to_owned()))
             };
@@ -84,7 +84,7 @@ impl FromStr for EPicRegion {
                 return Err(WifError::bad_request("Request string must not contain more than x,y,w,h parameters.".to_owned()))
             }
 
-            x = match p.get(0) {
+            x = match p.first() {
                 Some(v) => match v.parse::<f32>() {
                     Ok(f) => f,
                     Err(_) => return Err(WifError::bad_request("Request string must contain parameters for coordinates, width and height in the format x,y,w,h.".to_owned()))
@@ -123,17 +123,14 @@ impl FromStr for EPicRegion {
 }
 
 impl EPicRegion {
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_file(&self, img_view: &ImgView) -> Result<DynamicImage, WifError> {
-        match img_view.format {
-            image::ImageFormat::Png => {
-                match self.try_get_from_png(img_view) {
-                    Some(v) => {
-                        return Ok(v)
-                    },
-                    None => ()
-                }
-            },
-            _ => ()
+        let section = self.section(img_view)?;
+
+        if img_view.format == image::ImageFormat::Png {
+            if let Some(v) = self.read_png_section(&img_view.filepath, &section) {
+                return Ok(v)
+            }
         }
 
         let mut dyn_img = match image::open(&img_view.filepath) {
@@ -146,152 +143,69 @@ impl EPicRegion {
         Ok(dyn_img)
     }
 
-    pub fn mutate_image_region(&self, img: &mut DynamicImage) -> Result<(), WifError> {
-        match self {
-            EPicRegion::Full => (),
-            EPicRegion::Square => {
-                let dim = img.dimensions();
-                if img.width() > img.height() {
-                    *img = image::DynamicImage::crop(img, (dim.0 - dim.1)/2, 0, dim.1, dim.1);
-                } else if img.width() < img.height() {
-                    *img = image::DynamicImage::crop(img, 0, (dim.1 - dim.0)/2, dim.0, dim.0);
-                }
-            },
-            EPicRegion::Reg {x, y, w, h} => {
-                match self.rect_in_bounds(img.dimensions(), x.round() as u32, y.round() as u32, w.round() as u32, h.round() as u32) {
-                    (true, xp, yp, hp, wp) => {
-                        *img = image::DynamicImage::crop(img, xp, yp, wp, hp);
-                    },
-                    (false, _, _, _, _) => {
-                        return Err(WifError::bad_request("Region is out of bounds".to_owned()))
-                    }
-                };
-            },
-            EPicRegion::RegPerc {x, y, w, h} => {
-                match self.rect_in_bounds_perc(img.dimensions(), x, y, w, h) {
-                    (true, xp, yp, wp, hp) => {
-                        *img = image::DynamicImage::crop(img, xp, yp, wp, hp);
-                    },
-                    (false, _, _, _, _) => {
-                        return Err(WifError::bad_request("Region is out of bounds".to_owned()))
-                    }
-                }
-            }
-        }
-    
-        Ok(())
+    /// The part of the source image this region selects, computed from the
+    /// image dimensions alone so that it can be validated before decoding.
+    pub fn section(&self, img_view: &ImgView) -> Result<ImgSection, WifError> {
+        self.section_of(img_view.width(), img_view.height())
     }
-    
-    fn rect_in_bounds(&self, dim: (u32, u32), x: u32, y: u32, w: u32, h: u32) -> (bool, u32, u32, u32, u32) {
-        if x >= dim.0 || y >= dim.1 {
-            return (false, 0, 0, 0, 0)
-        }
-    
-        let mut wp = w;
-        let mut hp = h;
-    
-        if (x + w) > dim.0 {
-            wp = dim.0;
-        }
-        if (y + h) > dim.1 {
-            hp = dim.1;
-        }
-    
-        (true, x, y, wp, hp)
-    }
-    fn rect_in_bounds_perc(&self, dim: (u32, u32), x: &f32, y: &f32, w: &f32, h: &f32) -> (bool, u32, u32, u32, u32) {
-        let xp: u32 = (dim.0 as f32 * (x / 100f32)).round() as u32;
-        let yp: u32 = (dim.1 as f32 * (y / 100f32)).round() as u32;
-        let mut wp: u32 = (dim.0 as f32 * (w / 100f32)).round() as u32;
-        let mut hp: u32 = (dim.1 as f32 * (h / 100f32)).round() as u32;
-    
-        if xp >= dim.0 || yp >= dim.1 {
-            return (false, 0, 0, 0, 0)
-        }
-    
-        if (xp + wp) > dim.0 {
-            wp = dim.0;
-        }
-        if (yp + hp) > dim.1 {
-            hp = dim.1;
+
+    /// `full` if the region covers the whole image, otherwise the region in pixels.
+    pub fn canonical(&self, img_view: &ImgView) -> Result<String, WifError> {
+        let section = self.section(img_view)?;
+        if section.dimensions == img_view.dimensions {
+            Ok("full".to_owned())
+        } else {
+            Ok(format!("{},{},{},{}", section.x, section.y, section.width(), section.height()))
         }
-    
-        (true, xp, yp, wp, hp)
     }
-    
 
-    fn try_get_from_png(&self, img_view: &ImgView) -> Option<DynamicImage> {
-        let mut section: Option<ImgSection> = None;
-        match self {
+    fn section_of(&self, width: u32, height: u32) -> Result<ImgSection, WifError> {
+        let (x, y, w, h) = match self {
+            EPicRegion::Full => (0, 0, width, height),
             EPicRegion::Square => {
-                if img_view.width() > img_view.height() {
-                    section = Some(ImgSection {
-                        x: img_view.height() /2,
-                        y: 0,
-                        dimensions: Rect {
-                            width: img_view.height(),
-                            height: img_view.height()
-                        }
-                    });
-                } else if img_view.width() == img_view.height() {
-                    return None
+                if width > height {
+                    ((width - height) / 2, 0, height, height)
                 } else {
-                    section = Some(ImgSection {
-                        x: 0,
-                        y: img_view.width() /2,
-                        dimensions: Rect {
-                            width: img_view.width(),
-                            height: img_view.width()
-                        }
-                    });
+                    (0, (height - width) / 2, width, width)
                 }
             },
-
             EPicRegion::Reg {x, y, w, h} => {
-                let new_w = if w + x > img_view.width() as f32 {
-                    img_view.width() - x.round() as u32
-                } else { w.round() as u32 };
-                let new_h = if h + y > img_view.height() as f32 {
-                    img_view.height() - y.round() as u32
-                } else { h.round() as u32 };
-                section = Some(ImgSection {
-                    x: x.round() as u32,
-                    y: y.round() as u32,
-                    dimensions: Rect {
-                        width: new_w,
-                        height: new_h
-                    }
-                });
+                (x.round() as u32, y.round() as u32, w.round() as u32, h.round() as u32)
             },
-
-            EPicRegion::RegPerc { x, y, w, h } => {
-                let new_w = if w + x > img_view.width() as f32 {
-                    img_view.width()
-                } else { w.round() as u32 };
-                let new_h = if h + y > img_view.height() as f32 {
-                    img_view.height()
-                } else { h.round() as u32 };
-                section = Some(ImgSection {
-                    x: x.round() as u32,
-                    y: y.round() as u32,
-                    dimensions: Rect {
-                        width: new_w,
-                        height: new_h
-                    }
-                });
+            EPicRegion::RegPerc {x, y, w, h} => {
+                (
+                    (width as f32 * (x / 100f32)).round() as u32,
+                    (height as f32 * (y / 100f32)).round() as u32,
+                    (width as f32 * (w / 100f32)).round() as u32,
+                    (height as f32 * (h / 100f32)).round() as u32
+                )
             }
+        };
 
-            _ => ()
+        if x >= width || y >= height || w == 0 || h == 0 {
+            return Err(WifError::bad_request("Region is out of bounds".to_owned()))
         }
 
-        if let Some(s) = section {
-            self.read_png_section(&img_view.filepath, s)
-        } else {
-            None
+        Ok(ImgSection {
+            x,
+            y,
+            dimensions: Rect {
+                width: w.min(width - x),
+                height: h.min(height - y)
+            }
+        })
+    }
+
+    pub fn mutate_image_region(&self, img: &mut DynamicImage) -> Result<(), WifError> {
+        let section = self.section_of(img.width(), img.height())?;
+        if section.dimensions != (Rect { width: img.width(), height: img.height() }) {
+            *img = img.crop_imm(section.x, section.y, section.width(), section.height());
         }
+
+        Ok(())
     }
 
-    fn read_png_section(&self, filepath: &str, section: ImgSection) -> Option<DynamicImage> {
+    fn read_png_section(&self, filepath: &str, section: &ImgSection) -> Option<DynamicImage> {
         let f = match std::fs::File::open(filepath) {
             Ok(v) => v,
             Err(e) => {
@@ -301,45 +215,42 @@ impl EPicRegion {
         };
 
         let decoder = png::Decoder::new(f);
-        let (info, mut reader) = match decoder.read_info() {
+        let (_, mut reader) = match decoder.read_info() {
             Ok(v) => v,
             Err(e) => {
                 log::error!("{}", e);
                 return None
             }
         };
+        if reader.info().interlaced {
+            return None
+        }
+
+        // The decoder expands palettes and strips 16 bit samples,
+        // so the output layout can differ from the one in the header.
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != png::BitDepth::Eight {
+            return None
+        }
 
-        let color_multi = match info.color_type {
+        let pixel_size = match color_type {
             png::ColorType::RGB => 3,
             png::ColorType::RGBA => 4,
             png::ColorType::Grayscale => 1,
             png::ColorType::GrayscaleAlpha => 2,
-            png::ColorType::Indexed => 1
+            png::ColorType::Indexed => return None
         };
-        let bit_multi = match info.bit_depth {
-            png::BitDepth::Eight => 1,
-            png::BitDepth::Sixteen => 2,
-            _ => 1
-        };
-
-        let pixel_size = bit_multi * color_multi;
 
-        let buffer_size = (section.width() * section.height() * pixel_size) as usize;
-        let mut buf: Vec<u8> = vec![0; buffer_size];
+        let row_start = (section.x * pixel_size) as usize;
+        let row_end = ((section.x + section.width()) * pixel_size) as usize;
+        let mut buf: Vec<u8> = Vec::with_capacity((section.width() * section.height() * pixel_size) as usize);
 
-        let mut len = 0;
         for n in 0..(section.height() + section.y) {
             match reader.next_row() {
                 Ok(r) => {
                     if let Some(row) = r {
                         if n < section.y { continue }
-                        for i in 0..(section.width() * pixel_size + section.x) as usize {
-                            if i < section.x as usize { continue }
-                            if let Some(b) = row.get(i) {
-                                buf[len] = *b;
-                                len += 1;
-                            }
-                        }
+                        buf.extend_from_slice(row.get(row_start..row_end)?);
                     }
                 },
                 Err(e) => {
@@ -349,7 +260,7 @@ impl EPicRegion {
             }
         }
 
-        match info.color_type {
+        match color_type {
             png::ColorType::RGB => Some(DynamicImage::ImageRgb8(ImageBuffer::from_raw(section.width(), section.height(), buf)?)),
             png::ColorType::RGBA => Some(DynamicImage::ImageRgba8(ImageBuffer::from_raw(section.width(), section.height(), buf)?)),
             png::ColorType::Grayscale => Some(DynamicImage::ImageLuma8(ImageBuffer::from_raw(section.width(), section.height(), buf)?)),
@@ -33,6 +33,12 @@ pub fn mutate_image_rotation(rotation: &EPicRotation, img: &mut DynamicImage) ->
     Ok(())
 }
 
+impl EPicRotation {
+    pub fn canonical(&self) -> String {
+        let mirror = if self.mirrored { "!" } else { "" };
+        format!("{}{}", mirror, self.rotation % 360)
+    }
+}
 
 impl FromStr for EPicRotation {
     type Err = WifError;
@@ -42,19 +48,19 @@ impl FromStr for EPicRotation {
 
         if parts.len() > 1 {
             match parts[1].parse::<u32>() {
-                Ok(u) => return Ok(Self {
+                Ok(u) => Ok(Self {
                     rotation: u,
                     mirrored: true
                 }),
-                Err(_) => return Err(WifError::bad_request("Rotation cannot be parsed".to_owned()))
+                Err(_) => Err(WifError::bad_request("Rotation cannot be parsed".to_owned()))
             }
         } else {
             match parts[0].parse::<u32>() {
-                Ok(u) => return Ok(Self {
+                Ok(u) => Ok(Self {
                     rotation: u,
                     mirrored: false
                 }),
-                Err(_) => return Err(WifError::bad_request("Rotation cannot be parsed".to_owned()))
+                Err(_) => Err(WifError::bad_request("Rotation cannot be parsed".to_owned()))
             }
         }
     }
@@ -1,178 +1,430 @@
 use std::str::FromStr;
-use image::{DynamicImage, GenericImageView, imageops::{self}};
+use image::{DynamicImage, GenericImageView, imageops};
 
-use crate::wif_error::WifError;
+use crate::{config, wif_error::WifError};
+use super::{img_info::Rect, version::ApiVersion};
 
-#[derive(Debug)]
+/// The size parameter of an image request (IIIF Image API 3.0, section 4.2).
+/// A leading `^` allows the result to be larger than the extracted region.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EPicSize {
-    Max,
-    Width { w: f32, upscale: bool },
-    Height { h: f32, upscale: bool },
+    /// `max` / `^max`
+    Max { upscale: bool },
+    /// `w,` / `^w,`
+    Width { w: u32, upscale: bool },
+    /// `,h` / `^,h`
+    Height { h: u32, upscale: bool },
+    /// `pct:n` / `^pct:n`
     Perc { n: f32, upscale: bool },
-    WidthHeight { w: f32, h: f32, forced: bool, upscale: bool }
+    /// `w,h` / `^w,h`
+    WidthHeight { w: u32, h: u32, upscale: bool },
+    /// `!w,h` / `^!w,h`
+    BestFit { w: u32, h: u32, upscale: bool }
 }
 
 impl FromStr for EPicSize {
     type Err = WifError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "max" | "^max" => return Ok(EPicSize::Max),
-            _ => ()
-        }
+        let (upscale, part) = match s.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, s)
+        };
 
-        let part: &str;
-        let forced: bool;
-        let upscale: bool;
+        if part == "max" {
+            return Ok(EPicSize::Max { upscale })
+        }
 
-        let upscale_parts: Vec<&str> = s.split('^').collect();
-        if upscale_parts.len() > 1 {
-            upscale = true;
-        } else {
-            upscale = false;
+        if let Some(pct) = part.strip_prefix("pct:") {
+            return match pct.parse::<f32>() {
+                Ok(n) if n.is_finite() && n > 0.0 => Ok(EPicSize::Perc { n, upscale }),
+                _ => Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
+            }
         }
 
-        let parts: Vec<&str> = if upscale {
-            upscale_parts[1].split('!').collect()
-        } else {
-            upscale_parts[0].split('!').collect()
+        let (best_fit, part) = match part.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, part)
         };
 
-        if parts.len() > 1 {
-            forced = true;
-            part = parts[1];
-        } else {
-            forced = false;
-            part = parts[0];
+        let (w, h) = match part.split_once(',') {
+            Some(v) => v,
+            None => return Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
+        };
+
+        match (w.is_empty(), h.is_empty(), best_fit) {
+            (false, true, false) => Ok(EPicSize::Width { w: Self::parse_dimension(w)?, upscale }),
+            (true, false, false) => Ok(EPicSize::Height { h: Self::parse_dimension(h)?, upscale }),
+            (false, false, false) => Ok(EPicSize::WidthHeight { w: Self::parse_dimension(w)?, h: Self::parse_dimension(h)?, upscale }),
+            (false, false, true) => Ok(EPicSize::BestFit { w: Self::parse_dimension(w)?, h: Self::parse_dimension(h)?, upscale }),
+            _ => Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
+        }
+    }
+}
+
+impl EPicSize {
+    /// Parses the size syntax of the given API version. Image API 2.1 has no `^`,
+    /// accepts `full` for `max`, and lets every other form scale above the region.
+    pub fn parse(s: &str, version: ApiVersion) -> Result<Self, WifError> {
+        match version {
+            ApiVersion::V3 => Self::from_str(s),
+            ApiVersion::V2 => {
+                if s.starts_with('^') {
+                    return Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
+                }
+
+                Ok(match Self::from_str(if s == "full" { "max" } else { s })? {
+                    EPicSize::Max { .. } => EPicSize::Max { upscale: false },
+                    EPicSize::Width { w, .. } => EPicSize::Width { w, upscale: true },
+                    EPicSize::Height { h, .. } => EPicSize::Height { h, upscale: true },
+                    EPicSize::Perc { n, .. } => EPicSize::Perc { n, upscale: true },
+                    EPicSize::WidthHeight { w, h, .. } => EPicSize::WidthHeight { w, h, upscale: true },
+                    EPicSize::BestFit { w, h, .. } => EPicSize::BestFit { w, h, upscale: true }
+                })
+            }
         }
+    }
 
-        if let Some(n) = Self::parse_as_percent(part) {
-            return Ok(EPicSize::Perc { n, upscale })
+    fn parse_dimension(s: &str) -> Result<u32, WifError> {
+        match s.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
         }
+    }
+
+    /// Output dimensions for a region of the given size, checked against the
+    /// size limits before anything is decoded.
+    ///
+    /// `max` and `!w,h` are shrunk to fit the limits, all other forms are
+    /// rejected if they exceed them.
+    pub fn target(&self, region: &Rect, limits: &SizeLimits) -> Result<Rect, WifError> {
+        let rw = region.width as f64;
+        let rh = region.height as f64;
 
-        if let Some((w, h)) = Self::parse_as_width_and_height(part) {
-            return Ok(EPicSize::WidthHeight { w, h, forced, upscale })
+        let target = match *self {
+            EPicSize::Max { upscale } => {
+                let max_scale = if upscale { f64::INFINITY } else { 1.0 };
+                return Ok(limits.fit(region, max_scale))
+            },
+            EPicSize::BestFit { w, h, upscale } => {
+                let fitted = if !upscale && w >= region.width && h >= region.height {
+                    *region
+                } else if w as f64 / rw <= h as f64 / rh {
+                    Rect {
+                        width: w,
+                        height: ((rh * w as f64 / rw).round() as u32).clamp(1, h)
+                    }
+                } else {
+                    Rect {
+                        width: ((rw * h as f64 / rh).round() as u32).clamp(1, w),
+                        height: h
+                    }
+                };
+                return Ok(limits.fit(&fitted, 1.0))
+            },
+            EPicSize::Width { w, upscale } => {
+                Self::check_upscale(w > region.width, upscale)?;
+                Rect {
+                    width: w,
+                    height: (rh * w as f64 / rw).round() as u32
+                }
+            },
+            EPicSize::Height { h, upscale } => {
+                Self::check_upscale(h > region.height, upscale)?;
+                Rect {
+                    width: (rw * h as f64 / rh).round() as u32,
+                    height: h
+                }
+            },
+            EPicSize::Perc { n, upscale } => {
+                Self::check_upscale(n > 100.0, upscale)?;
+                Rect {
+                    width: (rw * n as f64 / 100.0).round() as u32,
+                    height: (rh * n as f64 / 100.0).round() as u32
+                }
+            },
+            EPicSize::WidthHeight { w, h, upscale } => {
+                Self::check_upscale(w > region.width || h > region.height, upscale)?;
+                Rect {
+                    width: w,
+                    height: h
+                }
+            }
+        };
+
+        if target.width == 0 || target.height == 0 {
+            return Err(WifError::bad_request("Size must not be 0".to_owned()))
         }
+        if !limits.allows(&target) {
+            return Err(WifError::bad_request(format!("Size {},{} exceeds the maximum allowed size", target.width, target.height)))
+        }
+
+        Ok(target)
+    }
 
-        if let Some(w) = Self::parse_as_width(part) {
-            return Ok(EPicSize::Width { w, upscale })
+    /// For 3.0 `max` if the result is the size `max` would produce, otherwise
+    /// `w,h`, prefixed with `^` if the result is larger than the region.
+    /// For 2.1 `full` if the region is not scaled, `w,` if the aspect ratio is
+    /// kept and `w,h` if it is not.
+    pub fn canonical(&self, region: &Rect, limits: &SizeLimits, version: ApiVersion) -> Result<String, WifError> {
+        let target = self.target(region, limits)?;
+        if version == ApiVersion::V2 {
+            if target == *region {
+                return Ok("full".to_owned())
+            }
+            let implied_height = (region.height as f64 * target.width as f64 / region.width as f64).round() as u32;
+            if target.height == implied_height {
+                return Ok(format!("{},", target.width))
+            }
+            return Ok(format!("{},{}", target.width, target.height))
         }
 
-        if let Some(h) = Self::parse_as_height(part) {
-            return Ok(EPicSize::Height { h, upscale })
+        if target == limits.fit(region, 1.0) {
+            Ok("max".to_owned())
+        } else if target.width > region.width || target.height > region.height {
+            Ok(format!("^{},{}", target.width, target.height))
+        } else {
+            Ok(format!("{},{}", target.width, target.height))
         }
+    }
 
-        Err(WifError::bad_request("Cannot parse parameter size".to_owned()))
+    fn check_upscale(exceeds_region: bool, upscale: bool) -> Result<(), WifError> {
+        if exceeds_region && !upscale {
+            return Err(WifError::bad_request("Size is larger than the region, use ^ to request upscaling".to_owned()))
+        }
+        Ok(())
     }
 }
 
-impl EPicSize {
-    fn parse_as_width(s: &str) -> Option<f32> {
-        let parts: Vec<&str> = s.split(',').collect();
+/// The maximum output size (`maxWidth`, `maxHeight`, `maxArea`) a request may resolve to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_area: u64
+}
 
-        if let Some(v) = parts.get(0) {
-            if let Ok(f) = v.parse::<f32>() {
-                return Some(f)
-            }
+impl SizeLimits {
+    pub fn from_config() -> Self {
+        // As in info.json, a missing maxHeight defaults to maxWidth.
+        let (max_width, max_height) = match (config::max_width(), config::max_height()) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, w),
+            (None, Some(h)) => (u32::MAX, h),
+            (None, None) => (u32::MAX, u32::MAX)
+        };
+
+        SizeLimits {
+            max_width,
+            max_height,
+            max_area: config::max_area()
+        }
+    }
+
+    /// Narrows the limits to the given caps, where there are any.
+    pub fn capped(&self, max_width: Option<u32>, max_height: Option<u32>, max_area: Option<u64>) -> Self {
+        // A cap on the width alone caps the height as well, as in info.json.
+        let max_height = max_height.or(max_width);
+        SizeLimits {
+            max_width: max_width.map(|w| w.min(self.max_width)).unwrap_or(self.max_width),
+            max_height: max_height.map(|h| h.min(self.max_height)).unwrap_or(self.max_height),
+            max_area: max_area.map(|a| a.min(self.max_area)).unwrap_or(self.max_area)
         }
-        None
     }
 
-    fn parse_as_height(s: &str) -> Option<f32> {
-        let parts: Vec<&str> = s.split(',').collect();
+    /// `maxWidth` and `maxHeight` as info.json states them: both absent when
+    /// unlimited, and `maxHeight` only if it differs from `maxWidth`.
+    pub fn info_dimensions(&self) -> (Option<u32>, Option<u32>) {
+        if self.max_width == u32::MAX && self.max_height == u32::MAX {
+            return (None, None)
+        }
+        let max_height = if self.max_height == self.max_width { None } else { Some(self.max_height) };
+        (Some(self.max_width), max_height)
+    }
 
-        if let Some(v) = parts.get(1) {
-            if let Ok(f) = v.parse::<f32>() {
-                return Some(f)
-            }
+    pub fn allows(&self, r: &Rect) -> bool {
+        r.width <= self.max_width
+            && r.height <= self.max_height
+            && r.width as u64 * r.height as u64 <= self.max_area
+    }
+
+    /// Scales `r` by at most `max_scale` while keeping it within the limits
+    /// and preserving its aspect ratio.
+    pub fn fit(&self, r: &Rect, max_scale: f64) -> Rect {
+        let w = r.width as f64;
+        let h = r.height as f64;
+        let scale = max_scale
+            .min(self.max_width as f64 / w)
+            .min(self.max_height as f64 / h)
+            .min((self.max_area as f64 / (w * h)).sqrt());
+
+        // The epsilon keeps exact scales such as 0.5 from flooring one pixel short.
+        let mut fitted = Rect {
+            width: ((w * scale + 1e-9).floor() as u32).max(1),
+            height: ((h * scale + 1e-9).floor() as u32).max(1)
+        };
+        // Guard against rounding pushing the area just over the limit.
+        while !self.allows(&fitted) && fitted.width > 1 && fitted.height > 1 {
+            fitted.width -= 1;
+            fitted.height = ((fitted.width as f64 * h / w).floor() as u32).max(1);
         }
-        None
+        fitted
+    }
+}
+
+/// Resizes the image to the dimensions computed by `EPicSize::target`.
+pub fn mutate_image_size(target: &Rect, img: &mut DynamicImage) -> Result<(), WifError> {
+    if img.width() != target.width || img.height() != target.height {
+        *img = img.resize_exact(target.width, target.height, imageops::FilterType::CatmullRom);
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION: Rect = Rect { width: 400, height: 300 };
+    const UNLIMITED: SizeLimits = SizeLimits { max_width: u32::MAX, max_height: u32::MAX, max_area: u64::MAX };
+    const LIMITED: SizeLimits = SizeLimits { max_width: 300, max_height: 300, max_area: 30000 };
 
-    fn parse_as_percent(s: &str) -> Option<f32> {
-        let parts: Vec<&str> = s.split("pct:").collect();
+    #[test]
+    fn parses_every_size_form() {
+        let cases = [
+            ("max", EPicSize::Max { upscale: false }),
+            ("^max", EPicSize::Max { upscale: true }),
+            ("150,", EPicSize::Width { w: 150, upscale: false }),
+            ("^150,", EPicSize::Width { w: 150, upscale: true }),
+            (",150", EPicSize::Height { h: 150, upscale: false }),
+            ("^,150", EPicSize::Height { h: 150, upscale: true }),
+            ("pct:50", EPicSize::Perc { n: 50.0, upscale: false }),
+            ("pct:100", EPicSize::Perc { n: 100.0, upscale: false }),
+            ("^pct:150.5", EPicSize::Perc { n: 150.5, upscale: true }),
+            ("150,100", EPicSize::WidthHeight { w: 150, h: 100, upscale: false }),
+            ("^150,100", EPicSize::WidthHeight { w: 150, h: 100, upscale: true }),
+            ("!150,100", EPicSize::BestFit { w: 150, h: 100, upscale: false }),
+            ("^!150,100", EPicSize::BestFit { w: 150, h: 100, upscale: true }),
+        ];
 
-        if let Some(v) = parts.get(1) {
-            if let Ok(f) = v.parse::<f32>() {
-                return Some(f)
+        for (s, expected) in cases.iter() {
+            match EPicSize::from_str(s) {
+                Ok(size) => assert_eq!(size, *expected, "size {}", s),
+                Err(e) => panic!("size {} was rejected: {}", s, e)
             }
         }
-        None
     }
 
-    fn parse_as_width_and_height(s: &str) -> Option<(f32, f32)> {
-        let parts: Vec<&str> = s.split(',').collect();
+    #[test]
+    fn parses_image_api_2_sizes() {
+        let cases = [
+            ("full", EPicSize::Max { upscale: false }),
+            ("max", EPicSize::Max { upscale: false }),
+            ("800,", EPicSize::Width { w: 800, upscale: true }),
+            (",600", EPicSize::Height { h: 600, upscale: true }),
+            ("pct:150", EPicSize::Perc { n: 150.0, upscale: true }),
+            ("800,600", EPicSize::WidthHeight { w: 800, h: 600, upscale: true }),
+            ("!800,600", EPicSize::BestFit { w: 800, h: 600, upscale: true }),
+        ];
 
-        if let Some(v) = parts.get(0) {
-            if let Ok(f1) = v.parse::<f32>() {
-                if let Some(b) = parts.get(1) {
-                    if let Ok(f2) = b.parse::<f32>() {
-                        return Some((f1, f2))
-                    }
-                }
+        for (s, expected) in cases.iter() {
+            match EPicSize::parse(s, ApiVersion::V2) {
+                Ok(size) => assert_eq!(size, *expected, "size {}", s),
+                Err(e) => panic!("size {} was rejected: {}", s, e)
             }
         }
-        None
+
+        for s in ["^max", "^800,", "^!800,600", "full,"].iter() {
+            assert!(EPicSize::parse(s, ApiVersion::V2).is_err(), "size {:?} was accepted", s);
+        }
     }
 
-}
+    #[test]
+    fn canonicalizes_image_api_2_sizes() {
+        let cases = [
+            ("full", UNLIMITED, "full"),
+            ("400,300", UNLIMITED, "full"),
+            ("200,", UNLIMITED, "200,"),
+            (",150", UNLIMITED, "200,"),
+            ("pct:50", UNLIMITED, "200,"),
+            ("200,150", UNLIMITED, "200,"),
+            ("!200,200", UNLIMITED, "200,"),
+            ("800,", UNLIMITED, "800,"),
+            ("200,200", UNLIMITED, "200,200"),
+            ("400,100", UNLIMITED, "400,100"),
+            ("800,300", UNLIMITED, "800,300"),
+            ("full", LIMITED, "200,"),
+            // 333 by 250, the height 333, rounds to as well.
+            ("pct:83.3", UNLIMITED, "333,"),
+        ];
 
-pub fn mutate_image_size(size: &EPicSize, img: &mut DynamicImage) -> Result<(), WifError> {
-    match size {
-        EPicSize::Max => (),
-        EPicSize::Height {h, upscale} => {
-            let mut nwidth = img.width();
+        for (s, limits, canonical) in cases.iter() {
+            let size = EPicSize::parse(s, ApiVersion::V2).unwrap();
+            assert_eq!(size.canonical(&REGION, limits, ApiVersion::V2).unwrap(), *canonical, "size {} with {:?}", s, limits);
+        }
+    }
 
-            if h.round() as u32 > img.height() {
-                if *upscale {
-                    let multi = h / img.height() as f32;
-                    nwidth = (nwidth as f32 * multi).round() as u32;
-                } else {
-                    return Err(WifError::bad_request("Size not allowed".to_owned()))
-                }
-            }
+    #[test]
+    fn rejects_malformed_sizes() {
+        let cases = [
+            "", "full", "^", "^^max", "max^", "!max", "pct:", "pct:0", "pct:-5", "^!pct:50", "!pct:50",
+            ",", "150", "0,", ",0", "0,0", "-150,", "150.5,", "!150,", "!,150", "!^150,150", "a,b", "150,100,50"
+        ];
 
-            *img = image::DynamicImage::resize(img, nwidth, h.round() as u32, image::imageops::FilterType::CatmullRom);
-        },
-        EPicSize::Width {w, upscale} => {
-            let mut nheight = img.height();
+        for s in cases.iter() {
+            assert!(EPicSize::from_str(s).is_err(), "size {:?} was accepted", s);
+        }
+    }
 
-            if w.round() as u32 > img.width() {
-                if *upscale {
-                    let multi = w / img.width() as f32;
-                    nheight = (nheight as f32 * multi).round() as u32;
-                } else {
-                    return Err(WifError::bad_request("Size not allowed".to_owned()))
-                }
-            }
+    #[test]
+    fn computes_target_dimensions() {
+        let cases = [
+            ("max", UNLIMITED, (400, 300)),
+            ("max", LIMITED, (200, 150)),
+            ("^max", LIMITED, (200, 150)),
+            ("^max", SizeLimits { max_width: 800, max_height: 800, max_area: u64::MAX }, (800, 600)),
+            ("200,", UNLIMITED, (200, 150)),
+            ("^800,", UNLIMITED, (800, 600)),
+            (",150", UNLIMITED, (200, 150)),
+            ("^,600", UNLIMITED, (800, 600)),
+            ("pct:50", UNLIMITED, (200, 150)),
+            ("pct:100", UNLIMITED, (400, 300)),
+            ("^pct:200", UNLIMITED, (800, 600)),
+            ("100,100", UNLIMITED, (100, 100)),
+            ("^500,100", UNLIMITED, (500, 100)),
+            ("!200,200", UNLIMITED, (200, 150)),
+            ("!400,150", UNLIMITED, (200, 150)),
+            ("!800,800", UNLIMITED, (400, 300)),
+            ("^!800,800", UNLIMITED, (800, 600)),
+            ("^!800,800", LIMITED, (200, 150)),
+            ("!800,800", LIMITED, (200, 150)),
+        ];
 
-            *img = image::DynamicImage::resize(img, w.round() as u32, nheight, image::imageops::FilterType::CatmullRom);
-        },
-        EPicSize::Perc {n, upscale} => {
-            if *n >= 100.0 && !upscale {
-                return Err(WifError::bad_request("Size not allowed".to_owned()))
+        for (s, limits, (width, height)) in cases.iter() {
+            let size = EPicSize::from_str(s).unwrap();
+            match size.target(&REGION, limits) {
+                Ok(r) => assert_eq!(r, Rect { width: *width, height: *height }, "size {} with {:?}", s, limits),
+                Err(e) => panic!("size {} with {:?} was rejected: {}", s, limits, e)
             }
+        }
+    }
 
-            let m_width = (img.width() as f32 * *n * 0.01).round() as u32;
-            let m_height = (img.height() as f32 * *n * 0.01).round() as u32;
-            *img = img.resize(m_width, m_height, imageops::FilterType::CatmullRom);
-        },
-        EPicSize::WidthHeight {w, h, forced, upscale} => {
-            let n_w = if *w > img.width() as f32 && !upscale {
-                return Err(WifError::bad_request("This size is not allowed".to_owned()))
-            } else {
-                w.round() as u32
-            };
-            let n_h = if *h > img.height() as f32 && !upscale {
-                return Err(WifError::bad_request("This size is not allowed".to_owned()))
-            } else {
-                h.round() as u32
-            };
-            if *forced {
-                *img = image::DynamicImage::resize(img, n_w, n_h, image::imageops::FilterType::CatmullRom);
-            } else {
-                *img = image::DynamicImage::resize_exact(img, n_w, n_h, image::imageops::FilterType::CatmullRom);
-            }
+    #[test]
+    fn rejects_targets_beyond_region_or_limits() {
+        let cases = [
+            ("401,", UNLIMITED),
+            (",301", UNLIMITED),
+            ("pct:100.5", UNLIMITED),
+            ("401,300", UNLIMITED),
+            ("400,301", UNLIMITED),
+            ("300,", LIMITED),
+            ("^,301", LIMITED),
+            ("^pct:200", LIMITED),
+            ("^400,10", LIMITED),
+            ("pct:0.01", UNLIMITED),
+        ];
+
+        for (s, limits) in cases.iter() {
+            let size = EPicSize::from_str(s).unwrap();
+            assert!(size.target(&REGION, limits).is_err(), "size {} with {:?} was accepted", s, limits);
         }
     }
-    Ok(())
 }
@@ -0,0 +1,29 @@
+use crate::config;
+
+/// The IIIF Image API versions wif serves. 3.0 lives under `/iiif`, 2.1 under `/iiif/2`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    V2,
+    V3
+}
+
+impl ApiVersion {
+    pub fn context(&self) -> &'static str {
+        match self {
+            ApiVersion::V2 => "http://iiif.io/api/image/2/context.json",
+            ApiVersion::V3 => "http://iiif.io/api/image/3/context.json"
+        }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::V2 => "/iiif/2",
+            ApiVersion::V3 => "/iiif"
+        }
+    }
+
+    /// Base URI of the image services of this version, without a trailing slash.
+    pub fn base_uri(&self) -> String {
+        format!("{}{}", config::base_address(), self.prefix())
+    }
+}
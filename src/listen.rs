@@ -0,0 +1,140 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr
+};
+use serde::{Deserialize, Serialize};
+use tide::listener::ConcurrentListener;
+
+use crate::config;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// An address to accept connections on: `127.0.0.1:8000`, `[::]:8000`, or a
+/// Unix domain socket such as `unix:/run/wif.sock`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("{} needs a path", s)),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => match s.parse() {
+                Ok(addr) => Ok(ListenAddr::Tcp(addr)),
+                Err(_) => Err(format!("{} is neither ip:port, [ipv6]:port nor unix:<path>", s))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display())
+        }
+    }
+}
+
+/// A listener for every configured address.
+pub fn listener() -> std::io::Result<ConcurrentListener<()>> {
+    listener_for(config::listen())
+}
+
+/// A listener for every address given.
+pub fn listener_for(addrs: Vec<ListenAddr>) -> std::io::Result<ConcurrentListener<()>> {
+    let mut listener = ConcurrentListener::new();
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp(a) => listener.add(a.to_string())?,
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(&path)?;
+                listener.add(path)?
+            }
+        }
+    }
+    Ok(listener)
+}
+
+/// A socket left behind by an earlier run would make binding fail. A socket
+/// something still accepts connections on belongs to a running instance, and
+/// is left alone.
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{io::{Error, ErrorKind}, os::unix::{fs::FileTypeExt, net::UnixStream}};
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                match UnixStream::connect(path) {
+                    Ok(_) => return Err(Error::new(ErrorKind::AddrInUse, format!("{} is in use by another process", path.display()))),
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                        if let Err(e) = std::fs::remove_file(path) {
+                            log::warn!("Cannot remove old socket {:?} --- {:?}", path, e);
+                        }
+                    },
+                    // Binding will fail and say why.
+                    Err(_) => ()
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_addresses() {
+        for s in ["127.0.0.1:8000", "[::]:8000", "[::1]:80", "unix:/run/wif.sock"] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), s);
+        }
+        assert_eq!("unix:wif.sock".parse::<ListenAddr>(), Ok(ListenAddr::Unix(PathBuf::from("wif.sock"))));
+        assert!("localhost:8000".parse::<ListenAddr>().is_err());
+        assert!("::1:8000".parse::<ListenAddr>().is_err());
+        assert!("unix:".parse::<ListenAddr>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn removes_only_stale_sockets() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("wif-listen-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let live = UnixListener::bind(&path).unwrap();
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        // Dropping the listener leaves the socket file behind, as a crash would.
+        drop(live);
+        assert!(path.exists());
+        assert!(remove_stale_socket(&path).is_ok());
+        assert!(!path.exists());
+    }
+}
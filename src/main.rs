@@ -1,78 +1,304 @@
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant
+};
 
+use async_std::prelude::FutureExt;
 use image::{ImageFormat, ImageOutputFormat};
 use log::info;
-use pretty_env_logger;
+use serde::Deserialize;
 
-use tide::{Body, Request, Response, StatusCode, http::mime, utils::After};
+use tide::{
+    Body, Request, Response, StatusCode,
+    http::{Mime, cookies::{Cookie, SameSite}, headers::HeaderValue, mime},
+    security::CorsMiddleware,
+    utils::After
+};
 
 mod wif_error;
 use wif_error::WifError;
 mod iiif;
 use iiif::{
-    img_info::ImgView,
-    info_json::IIIFInfo,
+    img_info::{ImgSection, ImgView, Rect},
+    info_json::{IIIFInfo, IIIFInfo2},
     region::EPicRegion,
     size::EPicSize,
     rotation::EPicRotation,
-    quality::EPicQuality
+    quality::EPicQuality,
+    version::ApiVersion
 };
 mod config;
+mod cli;
+use cli::Command;
+mod render_pool;
+use render_pool::RENDER_POOL;
+mod coalesce;
+use coalesce::RENDERS;
+mod metrics;
+mod presentation;
+mod glob;
+mod auth;
+mod signing;
+use signing::VerifySignature;
+mod policy;
+use policy::{ClientClass, Policy, Rule};
+mod api_keys;
+use api_keys::{API_KEYS, ApiKey};
+mod client;
+mod rate_limit;
+use rate_limit::{Family, RateLimit};
+mod reload;
+mod listen;
+mod access_log;
+use access_log::{AccessLog, RequestLog, Timings};
+mod trace;
+use trace::Span;
+mod health;
+mod shutdown;
 
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    ::std::env::set_var("RUST_LOG", "trace");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok((command, source)) => {
+            config::set_source(source);
+            command
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    match command {
+        Command::Serve => (),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(())
+        },
+        Command::HashPassword => {
+            let mut password = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut password) {
+                eprintln!("Cannot read password --- {:?}", e);
+                std::process::exit(1);
+            }
+            match auth::login::hash_password(password.trim_end_matches(&['\r', '\n'][..])) {
+                Ok(hash) => println!("{}", hash),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(())
+        },
+        Command::Sign(args) => {
+            match signing::cli(&args) {
+                Ok(url) => println!("{}", url),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(())
+        }
+    }
 
-    pretty_env_logger::init();
+    // RUST_LOG wins over the configuration, as it does for every other Rust program.
+    let mut logger = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => logger.parse_filters(&filters),
+        Err(_) => logger.parse_filters(&config::log_level())
+    };
+    logger.init();
     info!("Wif starting up...");
 
     let mut app = tide::new();
 
+    app.with(shutdown::Drain);
+    app.with(AccessLog);
+    app.with(metrics::RequestMetrics);
+
     app.with(After(|mut res: Response| async {
-        if let Some(err) = res.downcast_error::<WifError>() {
-            let status = err.status;
-            let msg = err.message.clone();
-            res.set_status(status);
-            res.set_body(msg);
+        if let Some(err) = res.downcast_error::<WifError>().cloned() {
+            err.write_to(&mut res);
         }
         Ok(res)
     }));
 
     app.with(tide_compress::CompressMiddleware::new());
+    // The auth probe service is called with an Authorization header from other origins.
+    app.with(CorsMiddleware::new()
+        .allow_headers("Authorization, Accept, Content-Type, X-API-Key".parse::<HeaderValue>()?)
+        .expose_headers("Link, Retry-After".parse::<HeaderValue>()?));
+
+    api_keys::spawn_maintenance();
+    reload::spawn_watchers();
+    trace::spawn_exporter();
 
     app.at("/").get(|_| async {
         Ok("Welcome at Wif! :-)")
     });
+    app.at("/health/live").get(health::live);
+    app.at("/health/ready").get(health::ready);
+    app.at("/version").get(health::version);
     app.at("/favicon.ico").get(|_| async {
         Ok(Body::from_file("./favicon.ico").await?)
     });
+    // With an admin address, metrics are only served there.
+    if config::metrics_listen().is_empty() {
+        app.at("/metrics").get(metrics::serve);
+    } else {
+        metrics::spawn_admin();
+    }
     app.at("/iiif/:identifier").get(redirect_info_json);
-    app.at("/iiif/:identifier/info.json").get(info_json);
-    app.at("/iiif/:identifier/:region/:size/:rotation/:quality").get(show_img);
-    app.listen(config::address_as_str()).await?;
+    app.at("/iiif/:identifier/info.json").with(RateLimit::new(Family::Info)).with(VerifySignature).get(info_json);
+    app.at("/iiif/:identifier/:region/:size/:rotation/:quality").with(RateLimit::new(Family::Image)).with(VerifySignature).get(show_img);
+    app.at("/iiif/manifest/:collection").get(manifest);
+    app.at("/iiif/collection").get(collection);
+    app.at("/iiif/collection/:path").get(collection);
+    // Middleware only applies to the methods added after it, so showing the form is free.
+    app.at("/iiif/auth/login").get(login_form).with(RateLimit::new(Family::Login)).post(login);
+    app.at("/iiif/auth/token").get(access_token);
+    app.at("/iiif/auth/logout").get(logout);
+    app.at("/iiif/auth/probe/:identifier").get(probe);
+    app.at("/iiif/2/:identifier").get(redirect_info_json_v2);
+    app.at("/iiif/2/:identifier/info.json").with(RateLimit::new(Family::Info)).with(VerifySignature).get(info_json_v2);
+    app.at("/iiif/2/:identifier/:region/:size/:rotation/:quality").with(RateLimit::new(Family::Image)).with(VerifySignature).get(show_img_v2);
+    // Once signalled, the listener is dropped, so no new connections are
+    // accepted, while those already accepted go on in their own tasks.
+    app.listen(listen::listener()?).race(async {
+        shutdown::signalled().await;
+        Ok(())
+    }).await?;
+
+    let open = shutdown::drain(config::shutdown_deadline()).await;
+    if open > 0 {
+        log::warn!("Shutting down with {} requests still open", open);
+    }
+    shutdown::flush().await;
+    info!("Wif stopped");
 
     Ok(())
 }
 
 async fn show_img(req: Request<()>) -> tide::Result<Response> {
+    traced_img(req, ApiVersion::V3).await
+}
+
+async fn show_img_v2(req: Request<()>) -> tide::Result<Response> {
+    traced_img(req, ApiVersion::V2).await
+}
+
+/// Serves an image request as the root span of a trace.
+async fn traced_img(req: Request<()>, version: ApiVersion) -> tide::Result<Response> {
+    let traceparent = req.header("traceparent").map(|h| h.last().as_str().to_owned());
+    let mut span = Span::root("show_img", traceparent.as_deref());
+    span.set_attribute("url.path", req.url().path().to_owned());
+    span.set_attribute("iiif.version", format!("{:?}", version));
+
+    let res = serve_img(req, version, span.context()).await;
+    let status = match &res {
+        Ok(r) => r.status(),
+        Err(e) => e.status()
+    };
+    span.set_attribute("http.status_code", u16::from(status));
+    if status.is_server_error() {
+        span.set_error(status.canonical_reason());
+    }
+    res
+}
+
+async fn serve_img(req: Request<()>, version: ApiVersion, trace: trace::Context) -> tide::Result<Response> {
+    let start = Instant::now();
     let img_identifier = req.param("identifier")?;
-    
-    let img_info = ImgView::for_identifier(img_identifier)?;
+
+    let img_info = {
+        let mut span = Span::child(&trace, "for_identifier");
+        span.set_attribute("iiif.identifier", img_identifier.to_owned());
+        ImgView::for_identifier(img_identifier)?
+    };
     let region = EPicRegion::from_str(req.param("region")?)?;
-    let size = EPicSize::from_str(req.param("size")?)?;
+    let size = EPicSize::parse(req.param("size")?, version)?;
     let rotation = EPicRotation::from_str(req.param("rotation")?)?;
     let mut quality = EPicQuality::from_str(req.param("quality")?)?;
 
-    match try_stream_unmodified(&img_info, &region, &size, &rotation, &quality).await {
-        Some(v) => return Ok(v),
-        None => ()
+    let api_key = api_key(&req)?;
+    let policy = Policy::for_image(&img_info);
+    let rule = policy.rule(client_class(&req, api_key.as_ref()));
+    Rule::check_request(rule, &img_info, &region, &size)?;
+
+    // Resolve the output size up front, so oversized requests are rejected before decoding.
+    let section = region.section(&img_info)?;
+    let limits = Rule::section_limits(rule, &img_info.dimensions, &section.dimensions);
+    let target = size.target(&section.dimensions, &limits)?;
+    auth::check_image(&img_info, &section.dimensions, &target, session_id(&req).as_deref())?;
+    let pixels = target.width as u64 * target.height as u64;
+    rate_limit::take_pixels(&req, pixels)?;
+    // Checked now, so no render is wasted on a used up key, but only counted
+    // once the image was served.
+    if let Some(key) = &api_key {
+        check_quota(key, pixels).await?;
+    }
+
+    let canonical = iiif::canonical::image_request(&img_info, &region, &size, &rotation, &quality, &limits, version)?;
+    let link = format!("<{}/{}>;rel=\"canonical\"", version.base_uri(), canonical);
+    let render_key = iiif::canonical::render_key(&img_info, &canonical, &limits);
+    // Filled in on the render thread, if this request is the one rendering.
+    let timings = Arc::new(Mutex::new(Timings { resolve: Some(start.elapsed()), ..Timings::default() }));
+
+    if let Some(mut v) = try_stream_unmodified(&img_info, &section, &target, &rotation, &quality).await {
+        if let Some(key) = &api_key {
+            charge(key, pixels).await?;
+        }
+        v.insert_header("Link", link);
+        v.insert_ext(request_log(canonical, &timings));
+        return Ok(v)
     }
 
-    let mut img = region.from_file(&img_info)?;
-    iiif::size::mutate_image_size(&size, &mut img)?;
-    iiif::rotation::mutate_image_rotation(&rotation, &mut img)?;
-    let buffer = iiif::quality::mutate_image_quality(&mut quality, &mut img)?;
+    let render_timings = timings.clone();
+    let buffer = RENDERS.run(&render_key, || async {
+        metrics::RENDERS.inc();
+        let buffer = RENDER_POOL.run(move || {
+            let source = metrics::source_label(&img_info.format);
+            let labels = [source.as_str(), metrics::output_label(quality.format())];
+
+            let stage = Instant::now();
+            let span = Span::child(&trace, "from_file");
+            let mut img = region.from_file(&img_info)?;
+            drop(span);
+            let decode = stage.elapsed();
+            metrics::DECODE_SECONDS.observe(&labels, decode);
+
+            let stage = Instant::now();
+            let span = Span::child(&trace, "mutate_image_size");
+            iiif::size::mutate_image_size(&target, &mut img)?;
+            drop(span);
+            metrics::RESIZE_SECONDS.observe(&labels, stage.elapsed());
+            let span = Span::child(&trace, "mutate_image_rotation");
+            iiif::rotation::mutate_image_rotation(&rotation, &mut img)?;
+            drop(span);
+            let transform = stage.elapsed();
+
+            let stage = Instant::now();
+            let span = Span::child(&trace, "mutate_image_quality");
+            let buffer = iiif::quality::mutate_image_quality(&mut quality, &mut img)?;
+            drop(span);
+            let encode = stage.elapsed();
+            metrics::ENCODE_SECONDS.observe(&labels, encode);
+            if let Ok(mut t) = render_timings.lock() {
+                t.decode = Some(decode);
+                t.transform = Some(transform);
+                t.encode = Some(encode);
+            }
+            Ok(buffer)
+        }).await?;
+
+        Ok(Arc::new(buffer))
+    }).await?;
+    if let Some(key) = &api_key {
+        charge(key, pixels).await?;
+    }
 
     let mimetype = match buffer.1 {
         ImageOutputFormat::Png => mime::PNG,
@@ -83,76 +309,313 @@ async fn show_img(req: Request<()>) -> tide::Result<Response> {
     let status = StatusCode::Ok;
     let mut res = Response::new(status);
     res.set_content_type(mimetype);
-    res.set_body(buffer.0);
+    res.insert_header("Link", link);
+    res.set_body(buffer.0.clone());
+    res.insert_ext(request_log(canonical, &timings));
     Ok(res)
 }
 
+fn request_log(canonical: String, timings: &Mutex<Timings>) -> RequestLog {
+    RequestLog {
+        canonical: Some(canonical),
+        timings: timings.lock().map(|t| *t).unwrap_or_default()
+    }
+}
+
 
 async fn redirect_info_json(req: Request<()>) -> tide::Result<Response> {
+    redirect_to_info_json(req, ApiVersion::V3)
+}
+
+async fn redirect_info_json_v2(req: Request<()>) -> tide::Result<Response> {
+    redirect_to_info_json(req, ApiVersion::V2)
+}
+
+fn redirect_to_info_json(req: Request<()>, version: ApiVersion) -> tide::Result<Response> {
     let img_path = req.param("identifier")?;
 
     let mut builder = Response::new(StatusCode::MovedPermanently);
-    builder.append_header("Location", format!("/iiif/{}/info.json", img_path));
+    builder.append_header("Location", format!("{}/{}/info.json", version.prefix(), img_path));
 
     Ok(builder)
 }
 
 async fn info_json(req: Request<()>) -> tide::Result<Response> {
+    serve_info_json(req, ApiVersion::V3).await
+}
+
+async fn info_json_v2(req: Request<()>) -> tide::Result<Response> {
+    serve_info_json(req, ApiVersion::V2).await
+}
+
+async fn serve_info_json(req: Request<()>, version: ApiVersion) -> tide::Result<Response> {
+    let start = Instant::now();
     let img_name = req.param("identifier")?;
     let img_info = ImgView::for_identifier(img_name)?;
-    let info_json = IIIFInfo::for_img(&img_info)?;
+
+    let api_key = api_key(&req)?;
+    let policy = Policy::for_image(&img_info);
+    let rule = policy.rule(client_class(&req, api_key.as_ref()));
+    Rule::check_access(rule)?;
+    let limits = Rule::limits(rule);
+
+    // The JSON-LD profile a client asks for decides which API version it gets.
+    let accept = req.header("Accept").map(|h| h.as_str());
+    let (version, content_type) = iiif::negotiate::info_json(accept, version);
+    let info_json = match version {
+        ApiVersion::V2 => IIIFInfo2::for_img(&img_info, &limits)?,
+        ApiVersion::V3 => IIIFInfo::for_img(&img_info, &limits)?
+    };
+    if let Some(key) = &api_key {
+        charge(key, 0).await?;
+    }
 
     let status = StatusCode::Ok;
     let mut res = Response::new(status);
-    res.set_content_type(mime::JSON);
     res.set_body(info_json);
+    res.set_content_type(content_type.as_str());
+    // Limits in info.json depend on who is asking.
+    res.insert_header("Vary", "Accept, Cookie, X-API-Key");
+    res.insert_ext(RequestLog {
+        canonical: Some(format!("{}/info.json", img_info.identifier)),
+        timings: Timings { resolve: Some(start.elapsed()), ..Timings::default() }
+    });
     Ok(res)
 }
 
-async fn try_stream_unmodified(img_view: &ImgView, region: &EPicRegion, size: &EPicSize, rotation: &EPicRotation, quality: &EPicQuality) -> Option<Response> {
-    match region {
-        EPicRegion::Full => (),
-        _ => return None
-    }
+async fn try_stream_unmodified(img_view: &ImgView, section: &ImgSection, target: &Rect, rotation: &EPicRotation, quality: &EPicQuality) -> Option<Response> {
+    let mime = unmodified_mime(img_view, section, target, rotation, quality)?;
+
+    let body = match Body::from_file(&img_view.filepath).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Error --- {:?}", e);
+            return None
+        }
+    };
+    let mut early_resp = Response::new(StatusCode::Ok);
+    early_resp.set_content_type(mime);
+    early_resp.set_body(body);
+    Some(early_resp)
+}
 
-    match size {
-        EPicSize::Max => (),
-        _ => return None
+/// The media type to send the source file as, if the request asks for exactly
+/// that file: the whole image, unscaled, unrotated and in its own format.
+fn unmodified_mime(img_view: &ImgView, section: &ImgSection, target: &Rect, rotation: &EPicRotation, quality: &EPicQuality) -> Option<Mime> {
+    if section.x != 0 || section.y != 0 || section.dimensions != img_view.dimensions || *target != img_view.dimensions {
+        return None
     }
 
     if rotation.rotation != 0 || rotation.mirrored {
         return None
     }
 
-    let mime;
     match quality {
         EPicQuality::Default(f) | EPicQuality::Color(f) => {
             match f {
-                ImageOutputFormat::Jpeg(_) => {
-                    if img_view.format == ImageFormat::Jpeg {
-                        mime = mime::JPEG;
-                    } else {return None}
-                },
-                ImageOutputFormat::Png => {
-                    if img_view.format == ImageFormat::Png {
-                        mime = mime::PNG;
-                    } else {return None}
-                }
-                _ => return None
+                ImageOutputFormat::Jpeg(_) if img_view.format == ImageFormat::Jpeg => Some(mime::JPEG),
+                ImageOutputFormat::Png if img_view.format == ImageFormat::Png => Some(mime::PNG),
+                _ => None
             }
         },
-        _ => return None
+        _ => None
     }
+}
 
-    let body = match Body::from_file(&img_view.filepath).await {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("Error --- {:?}", e);
-            return None
+async fn manifest(req: Request<()>) -> tide::Result<Response> {
+    let collection = req.param("collection")?.to_owned();
+    let class = client_class(&req, api_key(&req)?.as_ref());
+    // Reading the headers of every image in a folder is blocking work.
+    let manifest = async_std::task::spawn_blocking(move || presentation::manifest::for_collection(&collection, class)).await?;
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(manifest);
+    res.set_content_type(presentation_content_type().as_str());
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectionQuery {
+    page: Option<usize>
+}
+
+async fn collection(req: Request<()>) -> tide::Result<Response> {
+    let path = req.param("path").ok().map(|p| p.to_owned());
+    let page = req.query::<CollectionQuery>()?.page;
+    let class = client_class(&req, api_key(&req)?.as_ref());
+    let collection = async_std::task::spawn_blocking(move || presentation::collection::for_path(path.as_deref(), page, class)).await?;
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(collection);
+    res.set_content_type(presentation_content_type().as_str());
+    Ok(res)
+}
+
+fn presentation_content_type() -> String {
+    format!("application/ld+json;profile=\"{}\"", presentation::PRESENTATION_CONTEXT)
+}
+
+
+#[derive(Debug, Deserialize)]
+struct AccessQuery {
+    origin: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+    origin: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenQuery {
+    message_id: Option<String>,
+    origin: Option<String>
+}
+
+async fn login_form(req: Request<()>) -> tide::Result<Response> {
+    let origin = req.query::<AccessQuery>()?.origin.unwrap_or_default();
+    Ok(html(StatusCode::Ok, auth::login_page(&origin, false)))
+}
+
+async fn login(mut req: Request<()>) -> tide::Result<Response> {
+    let LoginForm { username, password, origin } = req.body_form().await?;
+    let user = username.clone();
+    // Hashing the password is expensive and the login command may be slow, so
+    // the check takes a place in the bounded render pool. Floods of logins are
+    // turned away with a 503 rather than tying up every blocking thread.
+    let granted = RENDER_POOL.run(move || Ok(auth::login::from_config().check(&user, &password))).await?;
+
+    if !granted {
+        info!("Failed login for {}", username);
+        return Ok(html(StatusCode::Unauthorized, auth::login_page(&origin.unwrap_or_default(), true)))
+    }
+
+    let mut res = html(StatusCode::Ok, auth::closing_page("Logged in"));
+    res.insert_cookie(session_cookie(auth::session::start(&username)));
+    Ok(res)
+}
+
+async fn access_token(req: Request<()>) -> tide::Result<Response> {
+    let query = req.query::<TokenQuery>()?;
+    let message = auth::access_token(session_id(&req).as_deref(), query.message_id.as_deref());
+
+    // Browser clients load the token service in an iframe and listen for a message,
+    // anything else gets the JSON right away.
+    match (query.message_id, query.origin) {
+        (Some(_), Some(origin)) => Ok(html(StatusCode::Ok, auth::token_page(&message, &origin))),
+        _ => {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(message);
+            Ok(res)
         }
-    };
-    let mut early_resp = Response::new(StatusCode::Ok);
-    early_resp.set_content_type(mime);
-    early_resp.set_body(body);
-    Some(early_resp)
+    }
+}
+
+async fn logout(req: Request<()>) -> tide::Result<Response> {
+    if let Some(id) = session_id(&req) {
+        auth::session::end(&id);
+    }
+    let mut res = html(StatusCode::Ok, auth::closing_page("Logged out"));
+    res.remove_cookie(session_cookie(String::new()));
+    Ok(res)
+}
+
+async fn probe(req: Request<()>) -> tide::Result<Response> {
+    let img = ImgView::for_identifier(req.param("identifier")?)?;
+    let token = req.header("Authorization")
+        .and_then(|h| h.last().as_str().strip_prefix("Bearer ").map(|t| t.trim().to_owned()));
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(auth::probe(&img, token.as_deref()));
+    Ok(res)
+}
+
+/// Checks a request against the key's quotas. Usage is read from its
+/// database, so this runs on a blocking thread.
+async fn check_quota(key: &ApiKey, pixels: u64) -> Result<(), WifError> {
+    let key = key.clone();
+    async_std::task::spawn_blocking(move || API_KEYS.check(&key, pixels)).await
+}
+
+/// Counts a served request against the key's quotas. Usage is written to its
+/// database, so this runs on a blocking thread.
+async fn charge(key: &ApiKey, pixels: u64) -> Result<(), WifError> {
+    let key = key.clone();
+    async_std::task::spawn_blocking(move || API_KEYS.charge(&key, pixels)).await
+}
+
+fn client_class(req: &Request<()>, api_key: Option<&ApiKey>) -> ClientClass {
+    if session_id(req).and_then(|id| auth::session::user(&id)).is_some() {
+        ClientClass::Authenticated
+    } else if api_key.is_some() {
+        ClientClass::ApiKey
+    } else {
+        ClientClass::Anonymous
+    }
+}
+
+/// The API key the client sent. Unknown keys are rejected rather than treated
+/// as anonymous, so typos show up.
+fn api_key(req: &Request<()>) -> Result<Option<ApiKey>, WifError> {
+    match client::api_key(req) {
+        Some(k) => match API_KEYS.lookup(&k) {
+            Some(key) => Ok(Some(key)),
+            None => Err(WifError::unauthorized("Unknown API key".to_owned()))
+        },
+        None => Ok(None)
+    }
+}
+
+fn session_id(req: &Request<()>) -> Option<String> {
+    req.cookie(auth::SESSION_COOKIE).map(|c| c.value().to_owned())
+}
+
+/// The session cookie has to reach us from viewers on other sites, which
+/// browsers only allow for secure cookies.
+fn session_cookie(id: String) -> Cookie<'static> {
+    let secure = config::base_address().starts_with("https://");
+    Cookie::build(auth::SESSION_COOKIE, id)
+        .path("/iiif")
+        .http_only(true)
+        .secure(secure)
+        .same_site(if secure { SameSite::None } else { SameSite::Lax })
+        .finish()
+}
+
+fn html(status: StatusCode, body: String) -> Response {
+    let mut res = Response::new(status);
+    res.set_content_type(mime::HTML);
+    res.set_body(body);
+    res
+}
+
+
+#[cfg(test)]
+mod tests {
+    use iiif::size::SizeLimits;
+    use super::*;
+
+    #[test]
+    fn streams_only_the_whole_image_unmodified() {
+        let img = ImgView::stub(1000, 800);
+        let unlimited = SizeLimits { max_width: u32::MAX, max_height: u32::MAX, max_area: u64::MAX };
+        let rotation = EPicRotation::from_str("0").unwrap();
+        let quality = EPicQuality::from_str("default.png").unwrap();
+        let mime = |region: &str, size: &str, version: ApiVersion| {
+            let section = EPicRegion::from_str(region).unwrap().section(&img).unwrap();
+            let target = EPicSize::parse(size, version).unwrap().target(&section.dimensions, &unlimited).unwrap();
+            unmodified_mime(&img, &section, &target, &rotation, &quality)
+        };
+
+        assert_eq!(mime("full", "max", ApiVersion::V3), Some(mime::PNG));
+        assert_eq!(mime("0,0,1000,800", "1000,800", ApiVersion::V3), Some(mime::PNG));
+        // Parts of the image scaled up to its full size still have to be rendered.
+        assert_eq!(mime("0,0,500,400", "^1000,800", ApiVersion::V3), None);
+        assert_eq!(mime("0,0,999,800", "^1000,800", ApiVersion::V3), None);
+        assert_eq!(mime("500,400,500,400", "1000,800", ApiVersion::V2), None);
+        assert_eq!(mime("full", "500,", ApiVersion::V3), None);
+    }
 }
@@ -0,0 +1,374 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::{
+        Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering}
+    },
+    task::{Context, Poll},
+    time::Duration
+};
+use async_std::{io::{BufRead, Read}, task};
+use image::{ImageFormat, ImageOutputFormat};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::{config, listen};
+
+/// Upper bounds in seconds of the buckets of every histogram.
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0)
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self) -> String {
+        format!("# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n", self.name, self.help, self.get())
+    }
+}
+
+/// A value that goes up and down, e.g. the number of jobs waiting.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Gauge {
+            name,
+            help,
+            value: AtomicI64::new(0)
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self) -> String {
+        format!("# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n", self.name, self.help, self.get())
+    }
+}
+
+/// Counters told apart by labels, e.g. requests by route and status.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>
+}
+
+impl CounterVec {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        CounterVec {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        if let Ok(mut values) = self.values.lock() {
+            *values.entry(label_values.iter().map(|v| v.to_string()).collect()).or_insert(0) += 1;
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!("# HELP {0} {1}\n# TYPE {0} counter\n", self.name, self.help);
+        if let Ok(values) = self.values.lock() {
+            for (label_values, n) in values.iter() {
+                out.push_str(&format!("{}{{{}}} {}\n", self.name, labels(self.labels, label_values), n));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+struct Observations {
+    /// Per bucket, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64
+}
+
+/// Durations sorted into buckets, told apart by labels.
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Observations>>
+}
+
+impl HistogramVec {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        HistogramVec {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], d: Duration) {
+        let secs = d.as_secs_f64();
+        if let Ok(mut values) = self.values.lock() {
+            let o = values.entry(label_values.iter().map(|v| v.to_string()).collect()).or_default();
+            if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+                o.buckets[i] += 1;
+            }
+            o.count += 1;
+            o.sum += secs;
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!("# HELP {0} {1}\n# TYPE {0} histogram\n", self.name, self.help);
+        if let Ok(values) = self.values.lock() {
+            for (label_values, o) in values.iter() {
+                let l = labels(self.labels, label_values);
+                let mut cumulative = 0;
+                for (bound, n) in BUCKETS.iter().zip(o.buckets.iter()) {
+                    cumulative += n;
+                    out.push_str(&format!("{}_bucket{{{},le=\"{}\"}} {}\n", self.name, l, bound, cumulative));
+                }
+                out.push_str(&format!("{}_bucket{{{},le=\"+Inf\"}} {}\n", self.name, l, o.count));
+                out.push_str(&format!("{}_sum{{{}}} {}\n", self.name, l, o.sum));
+                out.push_str(&format!("{}_count{{{}}} {}\n", self.name, l, o.count));
+            }
+        }
+        out
+    }
+}
+
+fn labels(names: &[&str], values: &[String]) -> String {
+    names.iter().zip(values.iter())
+        .map(|(n, v)| format!("{}=\"{}\"", n, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+pub static RENDERS: Counter = Counter::new("wif_renders_total", "Images rendered by the image pipeline.");
+pub static COALESCED_REQUESTS: Counter = Counter::new("wif_coalesced_requests_total", "Image requests served from a concurrent identical rendering.");
+pub static REQUESTS: CounterVec = CounterVec::new("wif_requests_total", "HTTP requests by route and status.", &["route", "status"]);
+pub static BYTES_SERVED: Counter = Counter::new("wif_bytes_served_total", "Bytes sent in response bodies, after compression.");
+pub static RENDERS_IN_FLIGHT: Gauge = Gauge::new("wif_renders_in_flight", "Images being rendered right now.");
+pub static RENDER_QUEUE_DEPTH: Gauge = Gauge::new("wif_render_queue_depth", "Render jobs waiting for a thread of the render pool.");
+pub static DECODE_SECONDS: HistogramVec = HistogramVec::new("wif_decode_seconds", "Time spent reading the region from the source image.", &["source", "output"]);
+pub static RESIZE_SECONDS: HistogramVec = HistogramVec::new("wif_resize_seconds", "Time spent scaling to the requested size.", &["source", "output"]);
+pub static ENCODE_SECONDS: HistogramVec = HistogramVec::new("wif_encode_seconds", "Time spent applying the quality and encoding the output.", &["source", "output"]);
+
+/// Label value of a source image format.
+pub fn source_label(format: &ImageFormat) -> String {
+    format!("{:?}", format).to_lowercase()
+}
+
+/// Label value of an output format.
+pub fn output_label(format: &ImageOutputFormat) -> &'static str {
+    match format {
+        ImageOutputFormat::Png => "png",
+        ImageOutputFormat::Jpeg(_) => "jpg",
+        _ => "other"
+    }
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = REQUESTS.render();
+    for c in [&RENDERS, &COALESCED_REQUESTS, &BYTES_SERVED] {
+        out.push_str(&c.render());
+    }
+    for g in [&RENDERS_IN_FLIGHT, &RENDER_QUEUE_DEPTH] {
+        out.push_str(&g.render());
+    }
+    for h in [&DECODE_SECONDS, &RESIZE_SECONDS, &ENCODE_SECONDS] {
+        out.push_str(&h.render());
+    }
+    out
+}
+
+/// `GET /metrics`
+pub async fn serve(_: Request<()>) -> tide::Result<Response> {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_content_type("text/plain; version=0.0.4");
+    res.set_body(render());
+    Ok(res)
+}
+
+/// Serves `/metrics` on the admin addresses, apart from the public ones.
+pub fn spawn_admin() {
+    let addrs = config::metrics_listen();
+    task::spawn(async move {
+        let mut app = tide::new();
+        app.at("/metrics").get(serve);
+        let listening = match listen::listener_for(addrs) {
+            Ok(l) => app.listen(l).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = listening {
+            log::error!("Cannot serve metrics on the admin address --- {:?}", e);
+        }
+    });
+}
+
+/// The route a path belongs to, so that requests can be counted without a
+/// label value per image.
+fn route(path: &str) -> &'static str {
+    let (prefix, rest) = match path.strip_prefix("/iiif/2/") {
+        Some(rest) => ("v2", rest),
+        None => match path.strip_prefix("/iiif/") {
+            Some(rest) => ("v3", rest),
+            None => return match path {
+                "/" => "root",
+                "/metrics" => "metrics",
+                p if p.starts_with("/health") || p == "/version" => "health",
+                _ => "other"
+            }
+        }
+    };
+
+    if prefix == "v3" {
+        for (start, name) in [("manifest/", "manifest"), ("collection", "collection"), ("auth/", "auth")] {
+            if rest.starts_with(start) {
+                return name
+            }
+        }
+    }
+    match (prefix, rest.split('/').count(), rest.ends_with("/info.json")) {
+        ("v3", _, true) => "info",
+        ("v2", _, true) => "info_v2",
+        ("v3", 5, _) => "image",
+        ("v2", 5, _) => "image_v2",
+        ("v3", 1, _) | ("v2", 1, _) => "redirect",
+        _ => "other"
+    }
+}
+
+/// Counts requests by route and status, and the bytes sent. Registered before
+/// compression, so the body it wraps is the compressed one.
+pub struct RequestMetrics;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for RequestMetrics {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        let route = route(req.url().path());
+        let mut res = next.run(req).await;
+        REQUESTS.inc(&[route, &u16::from(res.status()).to_string()]);
+
+        let body = res.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let mut counted = Body::from_reader(CountedBody { body }, len);
+        counted.set_mime(mime);
+        res.set_body(counted);
+        Ok(res)
+    }
+}
+
+/// A response body that adds the bytes read from it to `BYTES_SERVED`, so
+/// streamed and chunked bodies count too.
+struct CountedBody {
+    body: Body
+}
+
+impl Read for CountedBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            BYTES_SERVED.add(n as u64);
+        }
+        read
+    }
+}
+
+impl BufRead for CountedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        BYTES_SERVED.add(amt as u64);
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_routes_apart() {
+        assert_eq!(route("/iiif/book%2Fpage1/info.json"), "info");
+        assert_eq!(route("/iiif/2/book%2Fpage1/info.json"), "info_v2");
+        assert_eq!(route("/iiif/book%2Fpage1/full/max/0/default.jpg"), "image");
+        assert_eq!(route("/iiif/2/book%2Fpage1/full/full/0/default.jpg"), "image_v2");
+        assert_eq!(route("/iiif/book%2Fpage1"), "redirect");
+        assert_eq!(route("/iiif/manifest/book"), "manifest");
+        assert_eq!(route("/iiif/collection/series"), "collection");
+        assert_eq!(route("/iiif/auth/probe/x"), "auth");
+        assert_eq!(route("/metrics"), "metrics");
+        assert_eq!(route("/wp-login.php"), "other");
+    }
+
+    #[test]
+    fn counts_the_bytes_read_from_a_body() {
+        use async_std::io::ReadExt;
+
+        let before = BYTES_SERVED.get();
+        let mut body = CountedBody { body: Body::from_reader(async_std::io::Cursor::new(vec![7u8; 5000]), None) };
+        let mut sent = Vec::new();
+        task::block_on(body.read_to_end(&mut sent)).unwrap();
+        assert_eq!(sent.len(), 5000);
+        assert_eq!(BYTES_SERVED.get() - before, 5000);
+    }
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let h = HistogramVec::new("test_seconds", "Test.", &["source", "output"]);
+        h.observe(&["png", "jpg"], Duration::from_millis(3));
+        h.observe(&["png", "jpg"], Duration::from_millis(30));
+        h.observe(&["png", "jpg"], Duration::from_secs(60));
+
+        let out = h.render();
+        assert!(out.contains("test_seconds_bucket{source=\"png\",output=\"jpg\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{source=\"png\",output=\"jpg\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{source=\"png\",output=\"jpg\",le=\"5\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{source=\"png\",output=\"jpg\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_count{source=\"png\",output=\"jpg\"} 3\n"));
+    }
+}
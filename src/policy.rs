@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config, glob,
+    iiif::{
+        img_info::{ImgView, Rect, decode_identifier},
+        region::EPicRegion,
+        size::{EPicSize, SizeLimits}
+    },
+    wif_error::WifError
+};
+
+/// Who is asking, as far as policies are concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientClass {
+    Anonymous,
+    ApiKey,
+    Authenticated
+}
+
+/// What one client class may do with an image.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// No access at all, not even to info.json.
+    pub deny: bool,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_area: Option<u64>,
+    /// Rejects the `max` size, so only explicit sizes can be requested.
+    pub forbid_max: bool,
+    /// Rejects `full` and any other region covering the whole image, so it cannot
+    /// be downloaded at once.
+    pub forbid_full: bool
+}
+
+/// Rules per client class. Classes without a rule are not restricted.
+///
+/// A policy is read from the `policy` property of an image's metadata sidecar,
+/// or taken from the first entry of `policies` in the configuration file whose
+/// `match` glob matches the identifier.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub anonymous: Option<Rule>,
+    pub api_key: Option<Rule>,
+    pub authenticated: Option<Rule>
+}
+
+/// An entry of `policies` in the configuration file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyEntry {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    #[serde(default)]
+    pub anonymous: Option<Rule>,
+    #[serde(default)]
+    pub api_key: Option<Rule>,
+    #[serde(default)]
+    pub authenticated: Option<Rule>
+}
+
+impl Policy {
+    /// Denies every client class, for images whose own policy cannot be read.
+    pub fn deny_all() -> Self {
+        let deny = Some(Rule { deny: true, ..Rule::default() });
+        Policy { anonymous: deny.clone(), api_key: deny.clone(), authenticated: deny }
+    }
+
+    pub fn for_image(img: &ImgView) -> Self {
+        if let Some(p) = &img.metadata.policy {
+            return p.clone()
+        }
+
+        let rel_path = match decode_identifier(&img.identifier) {
+            Ok(p) => p,
+            Err(_) => return Policy::default()
+        };
+        match config::policies().into_iter().find(|e| glob::matches(&e.pattern, &rel_path)) {
+            Some(e) => Policy {
+                anonymous: e.anonymous,
+                api_key: e.api_key,
+                authenticated: e.authenticated
+            },
+            None => Policy::default()
+        }
+    }
+
+    pub fn rule(&self, class: ClientClass) -> Option<&Rule> {
+        match class {
+            ClientClass::Anonymous => self.anonymous.as_ref(),
+            ClientClass::ApiKey => self.api_key.as_ref(),
+            ClientClass::Authenticated => self.authenticated.as_ref()
+        }
+    }
+}
+
+impl Rule {
+    /// The configured size limits, narrowed by the caps of the rule, for the whole image.
+    pub fn limits(rule: Option<&Rule>) -> SizeLimits {
+        let limits = SizeLimits::from_config();
+        match rule {
+            Some(r) => limits.capped(r.max_width, r.max_height, r.max_area),
+            None => limits
+        }
+    }
+
+    /// The size limits for rendering `section` of an image of size `img`.
+    ///
+    /// The caps of the rule hold for the whole image at the scale the section is
+    /// rendered at, so they shrink with the section. Otherwise tiles at full
+    /// resolution would get around them.
+    pub fn section_limits(rule: Option<&Rule>, img: &Rect, section: &Rect) -> SizeLimits {
+        let limits = SizeLimits::from_config();
+        let rule = match rule {
+            Some(r) => r,
+            None => return limits
+        };
+
+        let w = section.width as f64 / img.width as f64;
+        let h = section.height as f64 / img.height as f64;
+        // Scaled separately, as a cap on the width alone caps the height as well.
+        limits.capped(
+            rule.max_width.map(|c| (c as f64 * w).floor() as u32),
+            rule.max_height.or(rule.max_width).map(|c| (c as f64 * h).floor() as u32),
+            rule.max_area.map(|c| (c as f64 * w * h).floor() as u64)
+        )
+    }
+
+    /// Rejects callers that are denied access at all.
+    pub fn check_access(rule: Option<&Rule>) -> Result<(), WifError> {
+        match rule {
+            Some(r) if r.deny => Err(WifError::forbidden("Access to this image is denied".to_owned())),
+            _ => Ok(())
+        }
+    }
+
+    /// Rejects image requests the rule forbids. The region is resolved first,
+    /// so `forbid_full` also catches regions that happen to cover the whole image.
+    pub fn check_request(rule: Option<&Rule>, img: &ImgView, region: &EPicRegion, size: &EPicSize) -> Result<(), WifError> {
+        Self::check_access(rule)?;
+        let rule = match rule {
+            Some(r) => r,
+            None => return Ok(())
+        };
+
+        if rule.forbid_max && matches!(size, EPicSize::Max { .. }) {
+            return Err(WifError::forbidden("The max size is not available for this image".to_owned()))
+        }
+        if rule.forbid_full && region.section(img)?.dimensions == img.dimensions {
+            return Err(WifError::forbidden("The full region is not available for this image".to_owned()))
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::*;
+
+    #[test]
+    fn checks_requests_against_rules() {
+        let img = ImgView::stub(1000, 800);
+        let rule = Rule { forbid_max: true, forbid_full: true, ..Rule::default() };
+        let full = EPicRegion::from_str("full").unwrap();
+        let square = EPicRegion::from_str("square").unwrap();
+        let max = EPicSize::from_str("max").unwrap();
+        let width = EPicSize::from_str("800,").unwrap();
+
+        assert!(Rule::check_request(None, &img, &full, &max).is_ok());
+        assert!(Rule::check_request(Some(&Rule::default()), &img, &full, &max).is_ok());
+        assert!(Rule::check_request(Some(&rule), &img, &square, &width).is_ok());
+        assert!(Rule::check_request(Some(&rule), &img, &square, &max).is_err());
+        assert!(Rule::check_request(Some(&rule), &img, &full, &width).is_err());
+        assert!(Rule::check_request(Some(&Rule { deny: true, ..Rule::default() }), &img, &square, &width).is_err());
+    }
+
+    #[test]
+    fn forbids_regions_covering_the_whole_image() {
+        let rule = Rule { forbid_full: true, ..Rule::default() };
+        let width = EPicSize::from_str("800,").unwrap();
+        let check = |img: &ImgView, region: &str| Rule::check_request(Some(&rule), img, &EPicRegion::from_str(region).unwrap(), &width);
+
+        let img = ImgView::stub(1000, 800);
+        for region in ["full", "0,0,1000,800", "0,0,5000,5000", "pct:0,0,100,100"] {
+            assert!(check(&img, region).is_err(), "{}", region);
+        }
+        for region in ["square", "0,0,999,800", "1,0,1000,800", "pct:0,0,50,100"] {
+            assert!(check(&img, region).is_ok(), "{}", region);
+        }
+        assert!(check(&ImgView::stub(800, 800), "square").is_err());
+    }
+
+    #[test]
+    fn caps_the_scale_of_the_whole_image() {
+        let img = Rect { width: 1000, height: 800 };
+        let rule = Rule { max_width: Some(800), ..Rule::default() };
+        let target = |region: &str, size: &str| {
+            let section = EPicRegion::from_str(region).unwrap().section(&ImgView::stub(1000, 800)).unwrap().dimensions;
+            EPicSize::from_str(size).unwrap().target(&section, &Rule::section_limits(Some(&rule), &img, &section))
+        };
+
+        assert_eq!(target("full", "max").unwrap(), Rect { width: 800, height: 640 });
+        assert!(target("full", "801,").is_err());
+        // A quarter of the image may be shown at no more than a quarter of the cap.
+        assert_eq!(target("0,0,500,400", "max").unwrap(), Rect { width: 400, height: 320 });
+        assert!(target("0,0,500,400", "500,").is_err());
+        assert!(target("0,0,256,256", "256,").is_err());
+        assert!(target("0,0,256,256", "204,").is_ok());
+        assert_eq!(Rule::section_limits(None, &img, &Rect { width: 10, height: 10 }), SizeLimits::from_config());
+    }
+
+    #[test]
+    fn reads_policies_for_each_client_class() {
+        let policy: Policy = serde_json::from_str(r#"{ "anonymous": { "max_width": 800, "forbid_max": true }, "authenticated": {} }"#).unwrap();
+
+        assert_eq!(policy.rule(ClientClass::Anonymous).and_then(|r| r.max_width), Some(800));
+        assert_eq!(policy.rule(ClientClass::Authenticated), Some(&Rule::default()));
+        assert_eq!(policy.rule(ClientClass::ApiKey), None);
+        assert!(serde_json::from_str::<Policy>(r#"{ "anonymous": { "max_size": 800 } }"#).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs, path::Path};
+use serde_json::{Value, json};
+
+use crate::{
+    config,
+    iiif::{
+        img_info::{decode_identifier, encode_identifier},
+        version::ApiVersion
+    },
+    policy::ClientClass,
+    wif_error::WifError
+};
+use super::{
+    PRESENTATION_CONTEXT, is_hidden, language_map, natural_cmp,
+    manifest::{ManifestMetadata, has_listed_images, manifest_id}
+};
+
+/// An entry of a collection, with the folder name it is sorted and paged by.
+struct Item {
+    name: String,
+    reference: Value
+}
+
+pub fn collection_id(rel_path: &str) -> String {
+    if rel_path.is_empty() {
+        format!("{}/collection", ApiVersion::V3.base_uri())
+    } else {
+        format!("{}/collection/{}", ApiVersion::V3.base_uri(), encode_identifier(rel_path))
+    }
+}
+
+/// A Presentation API 3.0 collection for a folder below the image root, or for
+/// the image root itself without a path.
+///
+/// Folders with subfolders become collections, folders with images the client
+/// gets to see become manifests. A folder holding both is listed as collection, which in turn
+/// lists the manifest of its own images first.
+///
+/// Collections with more entries than the configured page size are split into
+/// pages: without `page` they list one collection per page, which is served
+/// with `?page=n`. A page is filled by walking the folder in order until it is
+/// full, so serving it does not look at the folders on later pages.
+pub fn for_path(path: Option<&str>, page: Option<usize>, class: ClientClass) -> Result<Value, WifError> {
+    let rel_path = match path {
+        Some(p) => decode_identifier(p)?,
+        None => String::new()
+    };
+    let dir = Path::new(&config::image_path()).join(&rel_path);
+    if !dir.is_dir() || (!rel_path.is_empty() && is_hidden(&rel_path)) {
+        return Err(WifError::not_found(format!("{} not found", path.unwrap_or(""))))
+    }
+
+    let id = collection_id(&rel_path);
+    let label = folder_name(&rel_path);
+    let page_size = config::collection_page_size();
+
+    if let Some(n) = page {
+        if n == 0 {
+            return Err(WifError::not_found(format!("Page {} not found", n)))
+        }
+        let items = items(&dir, &rel_path, class)
+            .skip((n - 1).saturating_mul(page_size))
+            .take(page_size)
+            .map(|i| i.map(|i| i.reference))
+            .collect::<Result<Vec<Value>, WifError>>()?;
+        // The first page exists even for an empty folder.
+        if n > 1 && items.is_empty() {
+            return Err(WifError::not_found(format!("Page {} not found", n)))
+        }
+        return Ok(json!({
+            "@context": PRESENTATION_CONTEXT,
+            "id": format!("{}?page={}", id, n),
+            "type": "Collection",
+            "label": language_map(Value::String(format!("{} ({})", label, n))),
+            "partOf": [{ "id": id, "type": "Collection" }],
+            "items": items
+        }))
+    }
+
+    // Listing the pages needs every entry, for their number and labels.
+    let items = items(&dir, &rel_path, class).collect::<Result<Vec<Item>, WifError>>()?;
+    let pages = items.len().div_ceil(page_size).max(1);
+    match pages {
+        pages if pages > 1 => {
+            let items: Vec<Value> = items.chunks(page_size).enumerate()
+                .map(|(i, chunk)| {
+                    let first = &chunk[0].name;
+                    let last = &chunk[chunk.len() - 1].name;
+                    json!({
+                        "id": format!("{}?page={}", id, i + 1),
+                        "type": "Collection",
+                        "label": language_map(Value::String(format!("{} – {}", first, last)))
+                    })
+                })
+                .collect();
+            Ok(collection(&id, &label, items))
+        },
+        _ => Ok(collection(&id, &label, items.into_iter().map(|i| i.reference).collect()))
+    }
+}
+
+fn collection(id: &str, label: &str, items: Vec<Value>) -> Value {
+    json!({
+        "@context": PRESENTATION_CONTEXT,
+        "id": id,
+        "type": "Collection",
+        "label": language_map(Value::String(label.to_owned())),
+        "items": items
+    })
+}
+
+/// The entries of a folder in order. Whether the client gets to see an entry is
+/// only found out as the iterator reaches it, so callers only pay for what they take.
+fn items<'a>(dir: &'a Path, rel_path: &'a str, class: ClientClass) -> impl Iterator<Item = Result<Item, WifError>> + 'a {
+    // Images right in the root are not listed: a manifest of them would need an
+    // identifier, and the root has none.
+    let own = if rel_path.is_empty() {
+        None
+    } else {
+        item_if(has_listed_images(dir, rel_path, class), || Item {
+            name: folder_name(rel_path),
+            reference: manifest_reference(rel_path)
+        })
+    };
+
+    let children = subfolders(dir, rel_path).into_iter().filter_map(move |name| {
+        let child_rel = join(rel_path, &name);
+        let child_dir = dir.join(&name);
+
+        if !subfolders(&child_dir, &child_rel).is_empty() {
+            item_if(has_visible_content(&child_dir, &child_rel, class), || Item {
+                reference: json!({
+                    "id": collection_id(&child_rel),
+                    "type": "Collection",
+                    "label": language_map(Value::String(name.clone()))
+                }),
+                name
+            })
+        } else {
+            item_if(has_listed_images(&child_dir, &child_rel, class), || Item { reference: manifest_reference(&child_rel), name })
+        }
+    });
+    own.into_iter().chain(children)
+}
+
+fn item_if(visible: Result<bool, WifError>, item: impl FnOnce() -> Item) -> Option<Result<Item, WifError>> {
+    match visible {
+        Ok(true) => Some(Ok(item())),
+        Ok(false) => None,
+        Err(e) => Some(Err(e))
+    }
+}
+
+/// Whether a folder or one of its subfolders holds an image the client gets to
+/// see. Collections without one would only give away the names of their folders.
+fn has_visible_content(dir: &Path, rel_path: &str, class: ClientClass) -> Result<bool, WifError> {
+    if has_listed_images(dir, rel_path, class)? {
+        return Ok(true)
+    }
+    for name in subfolders(dir, rel_path) {
+        if has_visible_content(&dir.join(&name), &join(rel_path, &name), class)? {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+fn manifest_reference(rel_path: &str) -> Value {
+    let label = ManifestMetadata::load(rel_path).label
+        .unwrap_or_else(|| Value::String(folder_name(rel_path)));
+    json!({
+        "id": manifest_id(rel_path),
+        "type": "Manifest",
+        "label": language_map(label)
+    })
+}
+
+/// Visible subfolders, in natural order.
+fn subfolders(dir: &Path, rel_path: &str) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Cannot read folder {:?} --- {:?}", dir, e);
+            return vec![]
+        }
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().to_str().map(|s| s.to_owned()))
+        .filter(|n| !is_hidden(&join(rel_path, n)))
+        .collect();
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names
+}
+
+fn join(rel_path: &str, name: &str) -> String {
+    if rel_path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", rel_path, name)
+    }
+}
+
+fn folder_name(rel_path: &str) -> String {
+    let path = if rel_path.is_empty() { config::image_path() } else { rel_path.to_owned() };
+    match Path::new(&path).file_name().and_then(|n| n.to_str()) {
+        Some(n) => n.to_owned(),
+        None => path
+    }
+}
@@ -0,0 +1,276 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    auth, config,
+    policy::{ClientClass, Policy, Rule},
+    iiif::{
+        img_info::{ImgView, decode_identifier, encode_identifier},
+        metadata::{is_http_uri, read_sidecar, sidecar_paths},
+        region::EPicRegion,
+        size::EPicSize,
+        version::ApiVersion
+    },
+    wif_error::WifError
+};
+use super::{PRESENTATION_CONTEXT, is_hidden, is_listed, language_map, natural_cmp};
+
+/// Name of the sidecar file, without extension, that overrides manifest properties.
+const MANIFEST_SIDECAR: &str = "manifest";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetadataEntry {
+    pub label: Value,
+    pub value: Value
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct CanvasMetadata {
+    pub label: Option<Value>
+}
+
+/// Overrides for a generated manifest, read from `manifest.json`, `.yaml` or
+/// `.yml` in the folder, or in the same folder below the metadata directory.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ManifestMetadata {
+    pub label: Option<Value>,
+    pub summary: Option<Value>,
+    pub metadata: Vec<MetadataEntry>,
+    pub required_statement: Option<MetadataEntry>,
+    pub rights: Option<String>,
+    /// Canvas overrides by image file name without extension.
+    pub canvases: HashMap<String, CanvasMetadata>
+}
+
+impl ManifestMetadata {
+    pub fn load(rel_path: &str) -> Self {
+        let mut dirs = vec![];
+        if let Some(d) = config::metadata_path() {
+            dirs.push(Path::new(&d).join(rel_path));
+        }
+        dirs.push(Path::new(&config::image_path()).join(rel_path));
+
+        let path = match sidecar_paths(&dirs, MANIFEST_SIDECAR).into_iter().find(|p| p.exists()) {
+            Some(p) => p,
+            None => return ManifestMetadata::default()
+        };
+
+        let metadata: Result<ManifestMetadata, String> = read_sidecar(&path).and_then(|m: ManifestMetadata| {
+            match &m.rights {
+                Some(r) if !is_http_uri(r) => Err(format!("rights: {:?} is not an http(s) URI", r)),
+                _ => Ok(m)
+            }
+        });
+        match metadata {
+            Ok(m) => m,
+            Err(e) => {
+                log::error!("Ignoring manifest file {:?} --- {}", path, e);
+                ManifestMetadata::default()
+            }
+        }
+    }
+}
+
+/// Source images directly inside a folder, one per file name without extension, in natural order.
+pub fn images_in(dir: &Path) -> Result<Vec<String>, WifError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Cannot read folder {:?} --- {:?}", dir, e);
+            return Err(WifError::internal_error("Internal Server Error".to_owned()))
+        }
+    };
+
+    let mut stems: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && ImgView::is_source(p))
+        .filter_map(|p| p.file_stem().and_then(|s| s.to_str()).map(|s| s.to_owned()))
+        .collect();
+    stems.sort_by(|a, b| natural_cmp(a, b));
+    stems.dedup();
+    Ok(stems)
+}
+
+/// Whether a folder holds an image the client gets to see, which makes its manifest worth listing.
+pub fn has_listed_images(dir: &Path, rel_path: &str, class: ClientClass) -> Result<bool, WifError> {
+    Ok(images_in(dir)?.iter().any(|stem| {
+        ImgView::for_identifier(&encode_identifier(&format!("{}/{}", rel_path, stem)))
+            .map(|img| is_listed(&img, class))
+            .unwrap_or(false)
+    }))
+}
+
+pub fn manifest_id(rel_path: &str) -> String {
+    format!("{}/manifest/{}", ApiVersion::V3.base_uri(), encode_identifier(rel_path))
+}
+
+/// A Presentation API 3.0 manifest with one canvas per image in the folder
+/// that the client gets to see.
+pub fn for_collection(collection: &str, class: ClientClass) -> Result<Value, WifError> {
+    let rel_path = decode_identifier(collection)?;
+    let dir: PathBuf = Path::new(&config::image_path()).join(&rel_path);
+    if !dir.is_dir() || is_hidden(&rel_path) {
+        return Err(WifError::not_found(format!("{} not found", collection)))
+    }
+
+    let metadata = ManifestMetadata::load(&rel_path);
+    let id = manifest_id(&rel_path);
+
+    let mut canvases = vec![];
+    for stem in images_in(&dir)? {
+        let img = match ImgView::for_identifier(&encode_identifier(&format!("{}/{}", rel_path, stem))) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Leaving {} out of manifest {} --- {}", stem, id, e);
+                continue
+            }
+        };
+        if !is_listed(&img, class) {
+            continue
+        }
+
+        let label = metadata.canvases.get(&stem)
+            .and_then(|c| c.label.clone())
+            .unwrap_or_else(|| Value::String(stem.clone()));
+        let policy = Policy::for_image(&img);
+        canvases.push(canvas(&id, canvases.len() + 1, &img, label, policy.rule(class)));
+    }
+
+    let default_label = rel_path.rsplit('/').next().unwrap_or(&rel_path).to_owned();
+    let mut manifest = json!({
+        "@context": PRESENTATION_CONTEXT,
+        "id": id,
+        "type": "Manifest",
+        "label": language_map(metadata.label.unwrap_or(Value::String(default_label))),
+        "items": canvases
+    });
+
+    if let Some(s) = metadata.summary {
+        manifest["summary"] = language_map(s);
+    }
+    if !metadata.metadata.is_empty() {
+        manifest["metadata"] = metadata.metadata.into_iter().map(entry).collect();
+    }
+    if let Some(r) = metadata.required_statement {
+        manifest["requiredStatement"] = entry(r);
+    }
+    if let Some(r) = metadata.rights {
+        manifest["rights"] = Value::String(r);
+    }
+
+    Ok(manifest)
+}
+
+fn entry(e: MetadataEntry) -> Value {
+    json!({
+        "label": language_map(e.label),
+        "value": language_map(e.value)
+    })
+}
+
+/// A canvas showing the image as large as the client's rule lets it be seen.
+fn canvas(manifest_id: &str, n: usize, img: &ImgView, label: Value, rule: Option<&Rule>) -> Value {
+    let canvas_id = format!("{}/canvas/{}", manifest_id, n);
+    let service_id = format!("{}/{}", ApiVersion::V3.base_uri(), img.identifier);
+    let max = Rule::limits(rule).fit(&img.dimensions, 1.0);
+
+    let mut services = vec![json!({
+        "id": service_id,
+        "type": "ImageService3",
+        "profile": "level1"
+    })];
+    if auth::is_restricted(img) {
+        services.push(auth::probe_service(img));
+    }
+
+    let mut body = json!({
+        "type": "Image",
+        "format": "image/jpeg",
+        "width": max.width,
+        "height": max.height,
+        "service": services
+    });
+    // Without a request the rule allows, viewers have to go through the image service.
+    if let Some(request) = body_request(img, rule) {
+        body["id"] = Value::String(format!("{}/{}", service_id, request));
+    }
+
+    json!({
+        "id": canvas_id,
+        "type": "Canvas",
+        "label": language_map(label),
+        "width": img.width(),
+        "height": img.height(),
+        "items": [{
+            "id": format!("{}/page", canvas_id),
+            "type": "AnnotationPage",
+            "items": [{
+                "id": format!("{}/annotation", canvas_id),
+                "type": "Annotation",
+                "motivation": "painting",
+                "target": canvas_id,
+                "body": body
+            }]
+        }]
+    })
+}
+
+/// The image request painting the whole canvas, checked the way `serve_img`
+/// checks it. `None` if the rule refuses every such request, e.g. with `forbid_full`.
+fn body_request(img: &ImgView, rule: Option<&Rule>) -> Option<String> {
+    let region = EPicRegion::Full;
+    let section = region.section(img).ok()?;
+    let limits = Rule::section_limits(rule, &img.dimensions, &section.dimensions);
+    let max = limits.fit(&section.dimensions, 1.0);
+    let size = match rule {
+        Some(r) if r.forbid_max => EPicSize::WidthHeight { w: max.width, h: max.height, upscale: false },
+        _ => EPicSize::Max { upscale: false }
+    };
+
+    Rule::check_request(rule, img, &region, &size).ok()?;
+    size.target(&section.dimensions, &limits).ok()?;
+    let size = match size {
+        EPicSize::WidthHeight { w, h, .. } => format!("{},{}", w, h),
+        _ => "max".to_owned()
+    };
+    Some(format!("full/{}/0/default.jpg", size))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::*;
+
+    /// Parses a body request back and checks it as `serve_img` would.
+    fn served(img: &ImgView, rule: &Rule, request: &str) -> bool {
+        let parts: Vec<&str> = request.split('/').collect();
+        let region = EPicRegion::from_str(parts[0]).unwrap();
+        let size = EPicSize::parse(parts[1], ApiVersion::V3).unwrap();
+        let section = region.section(img).unwrap();
+        Rule::check_request(Some(rule), img, &region, &size).is_ok()
+            && size.target(&section.dimensions, &Rule::section_limits(Some(rule), &img.dimensions, &section.dimensions)).is_ok()
+    }
+
+    #[test]
+    fn paints_canvases_only_with_requests_the_rule_allows() {
+        let img = ImgView::stub(1000, 800);
+
+        for rule in [Rule::default(), Rule { max_width: Some(500), ..Rule::default() }, Rule { forbid_max: true, max_width: Some(500), ..Rule::default() }] {
+            let request = body_request(&img, Some(&rule)).unwrap();
+            assert!(served(&img, &rule, &request), "{} with {:?}", request, rule);
+        }
+        assert_eq!(body_request(&img, Some(&Rule { forbid_max: true, max_width: Some(500), ..Rule::default() })).as_deref(), Some("full/500,400/0/default.jpg"));
+
+        let no_full = Rule { forbid_full: true, ..Rule::default() };
+        assert!(!served(&img, &no_full, "full/max/0/default.jpg"));
+        assert_eq!(body_request(&img, Some(&no_full)), None);
+        let body = &canvas("m", 1, &img, Value::String("p1".to_owned()), Some(&no_full))["items"][0]["items"][0]["body"];
+        assert!(body.get("id").is_none());
+        assert_eq!(body["service"][0]["type"], "ImageService3");
+    }
+}
@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+use serde_json::{Value, json};
+
+use crate::{
+    config, glob,
+    iiif::img_info::{ImgView, decode_identifier},
+    policy::{ClientClass, Policy, Rule}
+};
+
+pub mod manifest;
+pub mod collection;
+
+pub const PRESENTATION_CONTEXT: &str = "http://iiif.io/api/presentation/3/context.json";
+
+/// Turns a plain string into a language map without language, and keeps language maps as they are.
+pub fn language_map(v: Value) -> Value {
+    match v {
+        Value::String(s) => json!({ "none": [s] }),
+        other => other
+    }
+}
+
+/// Whether a folder below the image root is left out of collections and manifests.
+///
+/// A folder is hidden if it or one of its parents starts with a dot or matches
+/// one of the configured patterns.
+pub fn is_hidden(rel_path: &str) -> bool {
+    let patterns = config::hidden_paths();
+    let mut prefix = String::new();
+    for component in rel_path.split('/') {
+        if component.starts_with('.') {
+            return true
+        }
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+        if patterns.iter().any(|p| glob::matches(p, &prefix)) {
+            return true
+        }
+    }
+    false
+}
+
+/// Whether a client gets to see an image in manifests and collections.
+///
+/// Protected images need a signed URL, which a manifest cannot carry, and
+/// images the client's policy denies access to would only fail to load.
+pub fn is_listed(img: &ImgView, class: ClientClass) -> bool {
+    match decode_identifier(&img.identifier) {
+        Ok(rel_path) => listed(&rel_path, &config::signing_protected(), &Policy::for_image(img), class),
+        Err(_) => false
+    }
+}
+
+fn listed(rel_path: &str, protected: &[String], policy: &Policy, class: ClientClass) -> bool {
+    !protected.iter().any(|p| glob::matches(p, rel_path)) && Rule::check_access(policy.rule(class)).is_ok()
+}
+
+/// Compares file names the way people number pages: `page2` before `page10`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let na = take_number(&mut a);
+                let nb = take_number(&mut b);
+                // Compare by value first, then leading zeros decide.
+                let ordering = na.trim_start_matches('0').len().cmp(&nb.trim_start_matches('0').len())
+                    .then_with(|| na.trim_start_matches('0').cmp(nb.trim_start_matches('0')))
+                    .then_with(|| na.len().cmp(&nb.len()));
+                if ordering != Ordering::Equal {
+                    return ordering
+                }
+            },
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase()).then(x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut n = String::new();
+    while let Some(c) = chars.peek().copied() {
+        if !c.is_ascii_digit() {
+            break
+        }
+        n.push(c);
+        chars.next();
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_file_names_naturally() {
+        let mut names = vec!["page10", "page2", "Page1", "page02", "cover", "page1"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["cover", "Page1", "page1", "page2", "page02", "page10"]);
+    }
+
+    #[test]
+    fn leaves_out_protected_and_denied_images() {
+        let protected = vec!["private/*".to_owned()];
+        let open = Policy::default();
+        assert!(listed("book/page1", &protected, &open, ClientClass::Anonymous));
+        assert!(!listed("private/page1", &protected, &open, ClientClass::Anonymous));
+        assert!(!listed("private/page1", &protected, &open, ClientClass::Authenticated));
+
+        let members_only = Policy {
+            anonymous: Some(Rule { deny: true, ..Rule::default() }),
+            api_key: Some(Rule { forbid_full: true, ..Rule::default() }),
+            authenticated: None
+        };
+        assert!(!listed("book/page1", &protected, &members_only, ClientClass::Anonymous));
+        assert!(listed("book/page1", &protected, &members_only, ClientClass::ApiKey));
+        assert!(listed("book/page1", &protected, &members_only, ClientClass::Authenticated));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::Instant
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tide::{Middleware, Next, Request};
+
+use crate::{api_keys::API_KEYS, client, config, wif_error::WifError};
+
+/// Number of buckets above which full, and therefore idle, buckets are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+/// New buckets between two prunes, so that pruning stays off most requests.
+const PRUNE_EVERY: usize = 1_000;
+/// Most buckets kept. Beyond it, the least recently updated are evicted.
+const MAX_BUCKETS: usize = 100_000;
+
+lazy_static! {
+    static ref BUCKETS: Mutex<Buckets> = Mutex::new(Buckets::new(MAX_BUCKETS));
+}
+
+/// A token bucket holding up to `burst` tokens and gaining `rate` tokens per second.
+///
+/// For info.json a token is a request, for images it is an output pixel, for
+/// the login it is an attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    pub rate: f64,
+    pub burst: f64
+}
+
+/// Routes that share a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    Info,
+    Image,
+    Login
+}
+
+impl Family {
+    fn config(&self) -> Option<BucketConfig> {
+        match self {
+            Family::Info => config::rate_limit_info(),
+            Family::Image => config::rate_limit_image(),
+            Family::Login => config::rate_limit_login()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst);
+        self.updated = now;
+    }
+}
+
+/// The buckets of all clients, bounded in number.
+struct Buckets {
+    map: HashMap<(Family, String), Bucket>,
+    max: usize,
+    inserted: usize
+}
+
+impl Buckets {
+    fn new(max: usize) -> Self {
+        Buckets { map: HashMap::new(), max, inserted: 0 }
+    }
+
+    fn get(&mut self, family: Family, client: String, config: &BucketConfig, now: Instant) -> &mut Bucket {
+        let key = (family, client);
+        if !self.map.contains_key(&key) {
+            self.make_room(now, |f| f.config());
+        }
+        let bucket = self.map.entry(key).or_insert(Bucket { tokens: config.burst, updated: now });
+        bucket.refill(config, now);
+        bucket
+    }
+
+    /// Called before a bucket is added. Drops full buckets every `PRUNE_EVERY`
+    /// insertions once there are many, and the least recently updated tenth
+    /// once there are `max`, so that neither happens on every request.
+    fn make_room(&mut self, now: Instant, config_of: impl Fn(Family) -> Option<BucketConfig>) {
+        self.inserted += 1;
+        if self.map.len() > PRUNE_THRESHOLD && self.inserted >= PRUNE_EVERY {
+            self.inserted = 0;
+            self.map.retain(|(f, _), b| match config_of(*f) {
+                Some(c) => {
+                    b.refill(&c, now);
+                    b.tokens < c.burst
+                },
+                None => false
+            });
+        }
+
+        if self.map.len() >= self.max {
+            let mut updated: Vec<Instant> = self.map.values().map(|b| b.updated).collect();
+            let (_, cutoff, _) = updated.select_nth_unstable((self.max / 10).max(1) - 1);
+            let cutoff = *cutoff;
+            self.map.retain(|_, b| b.updated > cutoff);
+        }
+    }
+}
+
+/// Limits each client per route family, by API key if it sent a valid one and
+/// by IP address otherwise, one token per request.
+///
+/// Image requests cost their output pixels on top, which are only known once
+/// the handler has resolved the request, so the handler calls `take_pixels`.
+/// The token taken here turns clients with an empty bucket away before the
+/// identifier is even resolved.
+pub struct RateLimit {
+    family: Family
+}
+
+impl RateLimit {
+    pub fn new(family: Family) -> Self {
+        RateLimit { family }
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<()> for RateLimit {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        if let Some(config) = self.family.config() {
+            take(self.family, client_key(&req), 1.0, &config)?;
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Takes the output pixels of an image request from the client's bucket.
+pub fn take_pixels(req: &Request<()>, pixels: u64) -> Result<(), WifError> {
+    match Family::Image.config() {
+        Some(config) => take(Family::Image, client_key(req), pixels as f64, &config),
+        None => Ok(())
+    }
+}
+
+fn take(family: Family, client: String, cost: f64, config: &BucketConfig) -> Result<(), WifError> {
+    let now = Instant::now();
+    let mut buckets = match BUCKETS.lock() {
+        Ok(v) => v,
+        Err(_) => return Err(WifError::internal_error("Rate limiter is poisoned".to_owned()))
+    };
+    let bucket = buckets.get(family, client, config, now);
+
+    // Requests larger than the whole bucket get through once it is full.
+    let cost = cost.min(config.burst);
+    if bucket.tokens >= cost {
+        bucket.tokens -= cost;
+        return Ok(())
+    }
+
+    let retry_after = ((cost - bucket.tokens) / config.rate).ceil().max(1.0) as u64;
+    Err(WifError::too_many_requests("Rate limit exceeded, please slow down.".to_owned(), retry_after))
+}
+
+fn client_key(req: &Request<()>) -> String {
+    // Made up keys are rejected later on, they must not buy a fresh bucket.
+    let key = client::api_key(req).and_then(|k| API_KEYS.lookup(&k));
+    bucket_name(key.map(|k| k.name).as_deref(), client::client_ip(req))
+}
+
+/// The bucket of a client. IPv6 clients are told apart by their /64 network,
+/// as a single host usually has all of it to pick addresses from. Clients
+/// without an address, e.g. on a Unix socket without `X-Forwarded-For`, share
+/// one bucket.
+fn bucket_name(key_name: Option<&str>, ip: Option<IpAddr>) -> String {
+    if let Some(name) = key_name {
+        return format!("key:{}", name)
+    }
+    match ip.map(|ip| ip.to_canonical()) {
+        Some(IpAddr::V4(ip)) => format!("ip:{}", ip),
+        Some(IpAddr::V6(ip)) => format!("ip:{}/64", Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+        None => "local".to_owned()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_buckets_over_time() {
+        let config = BucketConfig { rate: 100.0, burst: 1000.0 };
+        let start = Instant::now();
+        let mut bucket = Bucket { tokens: 0.0, updated: start };
+
+        bucket.refill(&config, start + std::time::Duration::from_secs(2));
+        assert!((bucket.tokens - 200.0).abs() < 1e-6);
+        bucket.refill(&config, start + std::time::Duration::from_secs(60));
+        assert!((bucket.tokens - 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_clients_that_drained_their_bucket() {
+        let config = BucketConfig { rate: 10.0, burst: 1000.0 };
+        let client = "ip:test-drain".to_owned();
+
+        assert!(take(Family::Image, client.clone(), 600.0, &config).is_ok());
+        let err = take(Family::Image, client.clone(), 600.0, &config).unwrap_err();
+        assert_eq!(err.retry_after, Some(20));
+        // Another family has a bucket of its own.
+        assert!(take(Family::Info, client, 600.0, &config).is_ok());
+    }
+
+    #[test]
+    fn names_buckets_by_key_ipv4_address_or_ipv6_network() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(bucket_name(Some("partner"), ip("1.2.3.4")), "key:partner");
+        assert_eq!(bucket_name(None, ip("1.2.3.4")), "ip:1.2.3.4");
+        assert_eq!(bucket_name(None, ip("::ffff:1.2.3.4")), "ip:1.2.3.4");
+        assert_eq!(bucket_name(None, ip("2001:db8:1:2:aaaa::1")), "ip:2001:db8:1:2::/64");
+        assert_eq!(bucket_name(None, ip("2001:db8:1:2:bbbb::2")), "ip:2001:db8:1:2::/64");
+        assert_eq!(bucket_name(None, ip("2001:db8:1:3::1")), "ip:2001:db8:1:3::/64");
+        assert_eq!(bucket_name(None, None), "local");
+    }
+
+    #[test]
+    fn prunes_full_buckets_only_every_so_often() {
+        let config = BucketConfig { rate: 1.0, burst: 10.0 };
+        let config_of = |_| Some(config);
+        let now = Instant::now();
+        let mut buckets = Buckets::new(MAX_BUCKETS);
+        for i in 0..=PRUNE_THRESHOLD {
+            buckets.map.insert((Family::Info, format!("ip:{}", i)), Bucket { tokens: 10.0, updated: now });
+        }
+        buckets.map.insert((Family::Info, "ip:busy".to_owned()), Bucket { tokens: 0.0, updated: now });
+
+        buckets.make_room(now, config_of);
+        assert_eq!(buckets.map.len(), PRUNE_THRESHOLD + 2);
+        buckets.inserted = PRUNE_EVERY - 1;
+        buckets.make_room(now, config_of);
+        assert_eq!(buckets.map.len(), 1);
+        assert!(buckets.map.contains_key(&(Family::Info, "ip:busy".to_owned())));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_updated_buckets() {
+        let config = BucketConfig { rate: 1.0, burst: 10.0 };
+        let start = Instant::now();
+        let mut buckets = Buckets::new(20);
+        for i in 0..30 {
+            let now = start + std::time::Duration::from_secs(i);
+            buckets.get(Family::Image, format!("ip:{}", i), &config, now).tokens = 0.0;
+            assert!(buckets.map.len() <= 20);
+        }
+        // Every other client evicts the two oldest.
+        assert_eq!(buckets.map.len(), 20);
+        assert!(!buckets.map.contains_key(&(Family::Image, "ip:9".to_owned())));
+        assert!(buckets.map.contains_key(&(Family::Image, "ip:10".to_owned())));
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime}
+};
+use async_std::task;
+
+use crate::{api_keys::API_KEYS, config, iiif::img_info::ImgView};
+
+/// How often the configuration file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reads the configuration again and passes it on to everything that keeps a
+/// copy of some settings. An invalid configuration is reported and ignored.
+pub fn reload() {
+    match config::reload() {
+        Ok(pending) => {
+            ImgView::forget_all();
+            API_KEYS.set_configured(config::api_keys());
+            log::info!("Configuration reloaded");
+            for name in pending {
+                log::warn!("{} changed, but only takes effect after a restart", name);
+            }
+        },
+        Err(e) => log::error!("Keeping the running configuration --- {}", e)
+    }
+}
+
+/// Reloads the configuration on SIGHUP and whenever its file changes.
+pub fn spawn_watchers() {
+    #[cfg(unix)]
+    match signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]) {
+        Ok(mut signals) => {
+            std::thread::spawn(move || {
+                for _ in signals.forever() {
+                    log::info!("Received SIGHUP, reloading configuration");
+                    reload();
+                }
+            });
+        },
+        Err(e) => log::error!("Cannot listen for SIGHUP --- {:?}", e)
+    }
+
+    let path = match config::file() {
+        Some(p) => p,
+        None => return
+    };
+    task::spawn(async move {
+        let mut last = modified(&path);
+        loop {
+            task::sleep(WATCH_INTERVAL).await;
+            let m = modified(&path);
+            if m != last {
+                last = m;
+                log::info!("{:?} changed, reloading configuration", path);
+                task::spawn_blocking(reload).await;
+            }
+        }
+    });
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
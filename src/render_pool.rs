@@ -0,0 +1,146 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, mpsc::{self, Receiver, SyncSender, TrySendError}},
+    thread
+};
+use async_std::channel;
+use lazy_static::lazy_static;
+
+use crate::{config, metrics, wif_error::WifError};
+
+/// Seconds a client is asked to wait when the render queue is full.
+const RETRY_AFTER_SECS: u64 = 2;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+lazy_static! {
+    pub static ref RENDER_POOL: RenderPool = RenderPool::new(config::render_threads(), config::render_queue());
+}
+
+/// A fixed set of worker threads for the CPU bound part of the image pipeline
+/// (decode, resize, rotate, encode) and for login checks, so that a single
+/// large request cannot block the async executor.
+///
+/// Jobs wait in a bounded queue. If the queue is full, `run` fails immediately
+/// with a 503 instead of piling up more work.
+pub struct RenderPool {
+    sender: SyncSender<Job>
+}
+
+impl RenderPool {
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for n in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("wif-render-{}", n))
+                .spawn(move || Self::work(receiver));
+            if let Err(e) = spawned {
+                log::error!("Cannot spawn render thread --- {:?}", e);
+            }
+        }
+
+        log::info!("Render pool started with {} threads and a queue of {}", threads.max(1), queue);
+
+        RenderPool {
+            sender
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(r) => r.recv(),
+                Err(_) => return
+            };
+
+            match job {
+                Ok(j) => {
+                    metrics::RENDER_QUEUE_DEPTH.dec();
+                    metrics::RENDERS_IN_FLIGHT.inc();
+                    // A panicking job drops its result sender, which the waiting
+                    // request reports as an internal error. The worker survives.
+                    if panic::catch_unwind(AssertUnwindSafe(j)).is_err() {
+                        log::error!("Render job panicked");
+                    }
+                    metrics::RENDERS_IN_FLIGHT.dec();
+                },
+                Err(_) => return
+            }
+        }
+    }
+
+    pub async fn run<F, T>(&self, f: F) -> Result<T, WifError>
+    where
+        F: FnOnce() -> Result<T, WifError> + Send + 'static,
+        T: Send + 'static
+    {
+        let (tx, rx) = channel::bounded(1);
+        let job: Job = Box::new(move || {
+            let _ = tx.try_send(f());
+        });
+
+        // Counted before sending, as a worker may take the job off the queue
+        // and count it out before `try_send` even returns.
+        metrics::RENDER_QUEUE_DEPTH.inc();
+        match self.sender.try_send(job) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                metrics::RENDER_QUEUE_DEPTH.dec();
+                log::warn!("Render queue is full, rejecting request");
+                return Err(WifError::service_unavailable("Server is busy, please try again later.".to_owned(), RETRY_AFTER_SECS))
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                metrics::RENDER_QUEUE_DEPTH.dec();
+                return Err(WifError::internal_error("Render pool is not running".to_owned()))
+            }
+        }
+
+        match rx.recv().await {
+            Ok(r) => r,
+            Err(_) => Err(WifError::internal_error("Render job failed".to_owned()))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+    use tide::{Response, StatusCode};
+    use super::*;
+
+    #[test]
+    fn rejects_jobs_once_the_queue_is_full() {
+        task::block_on(async {
+            let pool = Arc::new(RenderPool::new(1, 1));
+            let (started, running) = mpsc::channel::<()>();
+            let (open, gate) = mpsc::channel::<()>();
+
+            // One job keeps the only thread busy, another one fills the queue.
+            let busy = task::spawn({
+                let pool = pool.clone();
+                async move {
+                    pool.run(move || {
+                        let _ = started.send(());
+                        let _ = gate.recv();
+                        Ok(1)
+                    }).await
+                }
+            });
+            running.recv().unwrap();
+            assert!(pool.sender.try_send(Box::new(|| ())).is_ok());
+
+            let err = pool.run(|| Ok(3)).await.unwrap_err();
+            let mut res = Response::new(StatusCode::Ok);
+            err.write_to(&mut res);
+            assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+            assert_eq!(res.header("Retry-After").map(|h| h.as_str()), Some(RETRY_AFTER_SECS.to_string().as_str()));
+
+            open.send(()).unwrap();
+            assert_eq!(busy.await.unwrap(), 1);
+        });
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant}
+};
+use async_std::{channel, future, io::{BufRead, Read}, task};
+use tide::{Body, Middleware, Next, Request};
+
+use crate::trace;
+
+/// How often the number of open requests is checked while draining.
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+/// Longest each step of `flush` may take.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests whose response has not been sent completely yet.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a request as in flight until its response body has been written
+/// out, or the connection is gone. Registered first, so the body it wraps is
+/// the one sent.
+pub struct Drain;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for Drain {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        let guard = InFlight::start();
+        let mut res = next.run(req).await;
+
+        let body = res.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let mut guarded = Body::from_reader(GuardedBody { body, _guard: guard }, len);
+        guarded.set_mime(mime);
+        res.set_body(guarded);
+        Ok(res)
+    }
+}
+
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body that ends its request when it is dropped.
+struct GuardedBody {
+    body: Body,
+    _guard: InFlight
+}
+
+impl Read for GuardedBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl BufRead for GuardedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Resolves on the first SIGTERM or SIGINT. A second one ends the process
+/// at once, without waiting for requests.
+pub async fn signalled() {
+    let (tx, rx) = channel::bounded::<i32>(1);
+
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        match signal_hook::iterator::Signals::new([SIGTERM, SIGINT]) {
+            Ok(mut signals) => {
+                std::thread::spawn(move || {
+                    let mut received = false;
+                    for signal in signals.forever() {
+                        if received {
+                            log::warn!("Received a second signal, exiting without waiting for requests");
+                            std::process::exit(128 + signal);
+                        }
+                        received = true;
+                        let _ = tx.try_send(signal);
+                    }
+                });
+            },
+            Err(e) => log::error!("Cannot listen for SIGTERM --- {:?}", e)
+        }
+    }
+
+    // Without signal handling, the sender is dropped and this never resolves
+    // through `rx`; keep waiting in that case.
+    match rx.recv().await {
+        Ok(signal) => log::info!("Received signal {}, shutting down", signal),
+        Err(_) => std::future::pending::<()>().await
+    }
+}
+
+/// Waits until no request is in flight or the deadline has passed, and
+/// returns the number of requests still open.
+pub async fn drain(deadline: Duration) -> usize {
+    let start = Instant::now();
+    loop {
+        let open = IN_FLIGHT.load(Ordering::SeqCst);
+        if open == 0 || start.elapsed() >= deadline {
+            return open
+        }
+        task::sleep(DRAIN_POLL).await;
+    }
+}
+
+/// Writes out what is otherwise only written periodically. Each step gets
+/// `FLUSH_TIMEOUT`, so a hung disk or collector cannot keep the process alive.
+pub async fn flush() {
+    bounded("trace spans", trace::export()).await;
+}
+
+async fn bounded(name: &str, step: impl Future<Output = Result<(), String>>) {
+    match future::timeout(FLUSH_TIMEOUT, step).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => log::error!("{}", e),
+        Err(_) => log::error!("Gave up writing the {} after {:?}", name, FLUSH_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drains_once_bodies_are_dropped() {
+        task::block_on(async {
+            let body = GuardedBody { body: Body::from("done"), _guard: InFlight::start() };
+            assert_eq!(drain(Duration::from_millis(10)).await, 1);
+
+            let reader = Body::from_reader(body, Some(4));
+            assert_eq!(reader.into_string().await.unwrap(), "done");
+            assert_eq!(drain(Duration::from_secs(1)).await, 0);
+        });
+    }
+}
@@ -0,0 +1,240 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tide::{Middleware, Next, Request};
+
+use crate::{
+    config, glob,
+    iiif::{
+        img_info::{decode_identifier, encode_identifier},
+        version::ApiVersion
+    },
+    wif_error::WifError
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+
+/// Checks `?exp=...&sig=...` on image and info.json requests.
+///
+/// The signature is an HMAC-SHA256 over `<exp>\n<path>`, where the path is
+/// either the URL-encoded identifier, which covers every request for the image,
+/// or the identifier with region, size, rotation and quality, which covers just
+/// that one request. `kid` names the key it was made with; without it every
+/// configured key is tried, so keys can be rotated by adding the new key in
+/// front and dropping the old one once its URLs have expired.
+///
+/// Requests for protected identifiers need a valid signature, for all other
+/// identifiers a signature is optional but must be valid if present.
+pub struct VerifySignature;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for VerifySignature {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        verify(&req)?;
+        Ok(next.run(req).await)
+    }
+}
+
+/// The signature parameters of a request's query.
+#[derive(Debug, Default)]
+struct SignedQuery {
+    exp: Option<String>,
+    kid: Option<String>,
+    sig: Option<String>
+}
+
+fn verify(req: &Request<()>) -> Result<(), WifError> {
+    // Malformed identifiers are left to the endpoint to reject.
+    let rel_path = match req.param("identifier").map(decode_identifier) {
+        Ok(Ok(p)) => p,
+        _ => return Ok(())
+    };
+
+    let mut query = SignedQuery::default();
+    for (k, v) in req.url().query_pairs() {
+        match k.as_ref() {
+            "exp" => query.exp = Some(v.into_owned()),
+            "kid" => query.kid = Some(v.into_owned()),
+            "sig" => query.sig = Some(v.into_owned()),
+            _ => ()
+        }
+    }
+    let request = match (req.param("region"), req.param("size"), req.param("rotation"), req.param("quality")) {
+        (Ok(region), Ok(size), Ok(rotation), Ok(quality)) => Some(format!("{}/{}/{}/{}", region, size, rotation, quality)),
+        _ => None
+    };
+
+    check(&rel_path, request.as_deref(), &query, &config::signing_keys(), is_protected(&rel_path), now())
+}
+
+/// Checks the signature of a request for `rel_path`, and for the image request
+/// `<region>/<size>/<rotation>/<quality>` if it is one, at time `now`.
+fn check(rel_path: &str, request: Option<&str>, query: &SignedQuery, keys: &[(String, String)], protected: bool, now: u64) -> Result<(), WifError> {
+    let sig = match &query.sig {
+        Some(s) => s,
+        None if protected => return Err(WifError::forbidden("This image needs a signed URL".to_owned())),
+        None => return Ok(())
+    };
+    let exp = match query.exp.as_deref().map(str::parse::<u64>) {
+        Some(Ok(e)) => e,
+        _ => return Err(WifError::forbidden("Signed URL without valid expiry".to_owned()))
+    };
+    if exp < now {
+        return Err(WifError::forbidden("Signed URL has expired".to_owned()))
+    }
+
+    let identifier = encode_identifier(rel_path);
+    let mut paths = vec![identifier.clone()];
+    if let Some(r) = request {
+        paths.push(format!("{}/{}", identifier, r));
+    }
+
+    let signature = match from_hex(sig) {
+        Some(s) => s,
+        None => return Err(WifError::forbidden("Invalid signature".to_owned()))
+    };
+    let valid = keys.iter()
+        .filter(|(id, _)| query.kid.as_deref().map(|k| k == id).unwrap_or(true))
+        .any(|(_, secret)| paths.iter().any(|p| mac(secret, exp, p).verify_slice(&signature).is_ok()));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(WifError::forbidden("Invalid signature".to_owned()))
+    }
+}
+
+pub fn is_protected(rel_path: &str) -> bool {
+    config::signing_protected().iter().any(|p| glob::matches(p, rel_path))
+}
+
+fn mac(secret: &str, exp: u64, path: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}", exp, path).as_bytes());
+    mac
+}
+
+pub fn sign(secret: &str, exp: u64, path: &str) -> String {
+    mac(secret, exp, path).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// `wif sign <identifier> [--request <region>/<size>/<rotation>/<quality>] [--expires-in <secs>] [--key <id>] [--v2]`
+///
+/// Prints a signed URL for the image request, or for info.json if no request is
+/// given, in which case the signature covers every request for the identifier.
+pub fn cli(args: &[String]) -> Result<String, String> {
+    let mut identifier = None;
+    let mut request = None;
+    let mut expires_in = DEFAULT_EXPIRES_IN;
+    let mut key_id = None;
+    let mut version = ApiVersion::V3;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--request" => request = Some(args.next().ok_or("--request needs a value")?.trim_matches('/').to_owned()),
+            "--expires-in" => expires_in = args.next()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or("--expires-in needs a number of seconds")?,
+            "--key" => key_id = Some(args.next().ok_or("--key needs a value")?.to_owned()),
+            "--v2" => version = ApiVersion::V2,
+            a if a.starts_with("--") => return Err(format!("Unknown option {}", a)),
+            a => identifier = Some(a.to_owned())
+        }
+    }
+
+    let identifier = identifier.ok_or("Usage: wif sign <identifier> [--request <region>/<size>/<rotation>/<quality>] [--expires-in <secs>] [--key <id>] [--v2]")?;
+    let identifier = encode_identifier(identifier.trim_matches('/'));
+
+    let keys = config::signing_keys();
+    let (kid, secret) = match &key_id {
+        Some(k) => keys.iter().find(|(id, _)| id == k).ok_or(format!("No signing key {} in configuration file", k))?,
+        None => keys.first().ok_or("No signing keys in configuration file")?
+    };
+
+    let exp = now() + expires_in;
+    let (path, url) = match request {
+        Some(r) => {
+            let path = format!("{}/{}", identifier, r);
+            (path.clone(), format!("{}/{}", version.base_uri(), path))
+        },
+        None => (identifier.clone(), format!("{}/{}/info.json", version.base_uri(), identifier))
+    };
+
+    Ok(format!("{}?exp={}&kid={}&sig={}", url, exp, kid, sign(secret, exp, &path)))
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_cover_expiry_and_path() {
+        let sig = sign("secret", 1700000000, "embargo%2Fscan1");
+        let bytes = from_hex(&sig).unwrap();
+
+        assert!(mac("secret", 1700000000, "embargo%2Fscan1").verify_slice(&bytes).is_ok());
+        assert!(mac("secret", 1700000001, "embargo%2Fscan1").verify_slice(&bytes).is_err());
+        assert!(mac("secret", 1700000000, "embargo%2Fscan2").verify_slice(&bytes).is_err());
+        assert!(mac("other", 1700000000, "embargo%2Fscan1").verify_slice(&bytes).is_err());
+    }
+
+    #[test]
+    fn verifies_signed_requests() {
+        let keys = vec![("old".to_owned(), "old-secret".to_owned()), ("k1".to_owned(), "secret".to_owned())];
+        let now = 1700000000;
+        let exp = now + 60;
+        let signed = |sig: String, kid: Option<&str>| SignedQuery { exp: Some(exp.to_string()), kid: kid.map(|k| k.to_owned()), sig: Some(sig) };
+        let request = Some("full/max/0/default.jpg");
+
+        // A signature over the identifier covers every request, one over the request just that.
+        let for_image = signed(sign("secret", exp, "embargo%2Fscan1"), Some("k1"));
+        assert!(check("embargo/scan1", request, &for_image, &keys, true, now).is_ok());
+        assert!(check("embargo/scan1", None, &for_image, &keys, true, now).is_ok());
+        let for_request = signed(sign("secret", exp, "embargo%2Fscan1/full/max/0/default.jpg"), None);
+        assert!(check("embargo/scan1", request, &for_request, &keys, true, now).is_ok());
+
+        // Expired.
+        assert!(check("embargo/scan1", request, &for_image, &keys, true, exp + 1).is_err());
+        // Tampered with.
+        assert!(check("embargo/scan2", request, &for_image, &keys, true, now).is_err());
+        assert!(check("embargo/scan1", Some("full/max/0/default.png"), &for_request, &keys, true, now).is_err());
+        let later = SignedQuery { exp: Some((exp + 3600).to_string()), ..signed(sign("secret", exp, "embargo%2Fscan1"), Some("k1")) };
+        assert!(check("embargo/scan1", request, &later, &keys, true, now).is_err());
+        // Unknown or wrong key.
+        assert!(check("embargo/scan1", request, &signed(sign("secret", exp, "embargo%2Fscan1"), Some("k2")), &keys, true, now).is_err());
+        assert!(check("embargo/scan1", request, &signed(sign("secret", exp, "embargo%2Fscan1"), Some("old")), &keys, true, now).is_err());
+        assert!(check("embargo/scan1", request, &signed(sign("unknown", exp, "embargo%2Fscan1"), None), &keys, true, now).is_err());
+
+        // Only protected images need a signature at all.
+        assert!(check("embargo/scan1", request, &SignedQuery::default(), &keys, true, now).is_err());
+        assert!(check("open/scan1", request, &SignedQuery::default(), &keys, false, now).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        assert_eq!(from_hex("0aff"), Some(vec![10, 255]));
+        assert_eq!(from_hex("0af"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("aéa"), None);
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    fmt::Write as _,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+use async_std::{future, io::{BufReader, prelude::*}, net::TcpStream, task};
+use serde_json::{Value, json};
+use tide::http::Url;
+
+use crate::config;
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest a single export may take, from connecting to the status line.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Spans kept while the collector cannot be reached. Older ones are dropped.
+const MAX_PENDING: usize = 4096;
+
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const STATUS_ERROR: u8 = 2;
+
+static PENDING: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// The trace and span a new span belongs to, as carried by a W3C
+/// `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Context {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool
+}
+
+impl Context {
+    /// Parses `00-<trace id>-<parent id>-<flags>`. Invalid headers are
+    /// ignored, as the specification asks.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        let (version, trace_id, span_id, flags) = match parts.as_slice() {
+            [v, t, s, f] => (*v, *t, *s, *f),
+            // Later versions may append fields.
+            [v, t, s, f, ..] if *v != "00" => (*v, *t, *s, *f),
+            _ => return None
+        };
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None
+        }
+        let trace_id: [u8; 16] = hex_bytes(trace_id)?;
+        let span_id: [u8; 8] = hex_bytes(span_id)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Context { trace_id, span_id, sampled: flags & 1 == 1 })
+    }
+}
+
+/// A timed operation, sent to the collector when dropped. Spans are only
+/// recorded if `tracing.endpoint` is set and the trace is sampled.
+pub struct Span {
+    recording: bool,
+    context: Context,
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>
+}
+
+impl Span {
+    /// A span for a request, continuing the trace of the caller if it sent a
+    /// `traceparent` header.
+    pub fn root(name: &'static str, traceparent: Option<&str>) -> Self {
+        let parent = traceparent.and_then(Context::from_traceparent);
+        let context = Context {
+            trace_id: parent.map(|p| p.trace_id).unwrap_or_else(rand::random),
+            span_id: rand::random(),
+            sampled: parent.map(|p| p.sampled).unwrap_or(true)
+        };
+        Span::new(name, KIND_SERVER, context, parent.map(|p| p.span_id))
+    }
+
+    /// A span for a step of the operation `parent` is the context of.
+    pub fn child(parent: &Context, name: &'static str) -> Self {
+        let context = Context { span_id: rand::random(), ..*parent };
+        Span::new(name, KIND_INTERNAL, context, Some(parent.span_id))
+    }
+
+    fn new(name: &'static str, kind: u8, context: Context, parent: Option<[u8; 8]>) -> Self {
+        Span {
+            recording: context.sampled && config::tracing_endpoint().is_some(),
+            context,
+            parent,
+            name,
+            kind,
+            start: SystemTime::now(),
+            attributes: vec![],
+            error: None
+        }
+    }
+
+    pub fn context(&self) -> Context {
+        self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if self.recording {
+            self.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        self.error = Some(message.into());
+    }
+
+    /// The span in the OTLP JSON encoding.
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let attributes: Vec<Value> = self.attributes.iter()
+            .map(|(k, v)| json!({ "key": k, "value": any_value(v) }))
+            .collect();
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes
+        });
+        if let Some(parent) = &self.parent {
+            span["parentSpanId"] = Value::String(hex(parent));
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({ "code": STATUS_ERROR, "message": message });
+        }
+        span
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.recording {
+            return
+        }
+        let span = self.to_otlp(SystemTime::now());
+        if let Ok(mut pending) = PENDING.lock() {
+            if pending.len() >= MAX_PENDING {
+                pending.remove(0);
+            }
+            pending.push(span);
+        }
+    }
+}
+
+fn any_value(v: &Value) -> Value {
+    match v {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() })
+    }
+}
+
+/// The OTLP/HTTP request body for a batch of spans.
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": config::tracing_service_name() } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } }
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "wif" },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+/// Sends the recorded spans to the collector. Spans are kept for the next
+/// attempt if it cannot be reached.
+pub async fn export() -> Result<(), String> {
+    let endpoint = match config::tracing_endpoint() {
+        Some(e) => e,
+        None => return Ok(())
+    };
+    let spans = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => return Ok(())
+    };
+    if spans.is_empty() {
+        return Ok(())
+    }
+
+    let body = export_request(spans.clone()).to_string();
+    match post(&endpoint, &body, EXPORT_TIMEOUT).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            if let Ok(mut pending) = PENDING.lock() {
+                let newer = std::mem::replace(&mut *pending, spans);
+                pending.extend(newer);
+                let excess = pending.len().saturating_sub(MAX_PENDING);
+                pending.drain(..excess);
+            }
+            Err(format!("Cannot export spans to {} --- {}", endpoint, e))
+        }
+    }
+}
+
+/// A minimal HTTP/1.1 POST of a JSON body, enough for an OTLP collector.
+/// Gives up after `limit`, so a hung collector cannot stall the exporter.
+async fn post(endpoint: &str, body: &str, limit: Duration) -> Result<(), String> {
+    match future::timeout(limit, exchange(endpoint, body)).await {
+        Ok(r) => r,
+        Err(_) => Err(format!("no answer within {:?}", limit))
+    }
+}
+
+async fn exchange(endpoint: &str, body: &str) -> Result<(), String> {
+    let url = Url::parse(endpoint).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("no host")?;
+    let port = url.port_or_known_default().ok_or("no port")?;
+    let target = match url.query() {
+        Some(q) => format!("{}?{}", url.path(), q),
+        None => url.path().to_owned()
+    };
+
+    let mut stream = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let mut request = String::new();
+    let _ = write!(
+        request,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        target, host, port, body.len()
+    );
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+    // Only the status line matters, the rest of the response is not waited for.
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await.map_err(|e| e.to_string())?;
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status.starts_with('2') {
+        Ok(())
+    } else {
+        Err(format!("collector answered {}", status_line.trim_end()))
+    }
+}
+
+/// Periodically sends the recorded spans to the collector.
+pub fn spawn_exporter() {
+    task::spawn(async {
+        loop {
+            task::sleep(EXPORT_INTERVAL).await;
+            if let Err(e) = export().await {
+                log::warn!("{}", e);
+            }
+        }
+    });
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_bytes<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None
+    }
+    let mut bytes = [0; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_traceparent_headers() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = Context::from_traceparent(header).unwrap();
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+
+        assert!(!Context::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap().sampled);
+        assert!(Context::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-later").is_some());
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01"
+        ] {
+            assert_eq!(Context::from_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn posts_to_a_collector() {
+        task::block_on(async {
+            let collector = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}/v1/traces?tenant=a", collector.local_addr().unwrap());
+            let received = task::spawn(async move {
+                let (mut stream, _) = collector.accept().await.unwrap();
+                let mut buf = vec![0; 65536];
+                let mut request = String::new();
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+                    if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                        let length = head.lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .and_then(|l| l.parse::<usize>().ok())
+                            .unwrap();
+                        if body.len() >= length {
+                            break
+                        }
+                    }
+                }
+                // Answers, but keeps the connection open.
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+                (request, stream)
+            });
+
+            let parent = Context::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+            let mut span = Span::child(&parent, "from_file");
+            span.recording = true;
+            span.set_attribute("wif.width", 100);
+            let body = export_request(vec![span.to_otlp(SystemTime::now())]).to_string();
+            span.recording = false;
+
+            post(&endpoint, &body, Duration::from_secs(5)).await.unwrap();
+            let (request, _) = received.await;
+            assert!(request.starts_with("POST /v1/traces?tenant=a HTTP/1.1\r\n"));
+            assert!(request.contains("\"traceId\":\"4bf92f3577b34da6a3ce929d0e0e4736\""));
+            assert!(request.contains("\"parentSpanId\":\"00f067aa0ba902b7\""));
+            assert!(request.contains("\"name\":\"from_file\""));
+        });
+    }
+
+    #[test]
+    fn gives_up_on_a_hung_collector() {
+        task::block_on(async {
+            let collector = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+            // Accepts, reads nothing and never answers.
+            let _hung = task::spawn(async move {
+                let (stream, _) = collector.accept().await.unwrap();
+                task::sleep(Duration::from_secs(60)).await;
+                drop(stream);
+            });
+
+            let start = std::time::Instant::now();
+            assert!(post(&endpoint, "{}", Duration::from_millis(200)).await.is_err());
+            assert!(start.elapsed() < Duration::from_secs(5));
+        });
+    }
+}
@@ -1,11 +1,12 @@
 use std::fmt::Display;
 
-use tide::StatusCode;
+use tide::{Response, StatusCode};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WifError {
     pub status: StatusCode,
-    pub message: String
+    pub message: String,
+    pub retry_after: Option<u64>
 }
 impl Display for WifError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
@@ -19,19 +20,59 @@ impl WifError {
     pub fn not_found(m: String) -> Self {
         WifError {
             status: StatusCode::NotFound,
-            message: m
+            message: m,
+            retry_after: None
         }
     }
     pub fn bad_request(m: String) -> Self {
         WifError {
             status: StatusCode::BadRequest,
-            message: m
+            message: m,
+            retry_after: None
+        }
+    }
+    pub fn unauthorized(m: String) -> Self {
+        WifError {
+            status: StatusCode::Unauthorized,
+            message: m,
+            retry_after: None
+        }
+    }
+    pub fn forbidden(m: String) -> Self {
+        WifError {
+            status: StatusCode::Forbidden,
+            message: m,
+            retry_after: None
         }
     }
     pub fn internal_error(m: String) -> Self {
         WifError {
             status: StatusCode::InternalServerError,
-            message: m
+            message: m,
+            retry_after: None
+        }
+    }
+    pub fn too_many_requests(m: String, retry_after: u64) -> Self {
+        WifError {
+            status: StatusCode::TooManyRequests,
+            message: m,
+            retry_after: Some(retry_after)
+        }
+    }
+    pub fn service_unavailable(m: String, retry_after: u64) -> Self {
+        WifError {
+            status: StatusCode::ServiceUnavailable,
+            message: m,
+            retry_after: Some(retry_after)
+        }
+    }
+
+    /// Turns the response into the one sent for this error.
+    pub fn write_to(&self, res: &mut Response) {
+        res.set_status(self.status);
+        res.set_body(self.message.clone());
+        if let Some(secs) = self.retry_after {
+            res.insert_header("Retry-After", secs.to_string());
         }
     }
 }